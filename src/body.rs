@@ -1,37 +1,76 @@
 use std;
+use std::fs::File;
+use std::io::Read;
 
+use futures_cpupool::{CpuFuture, CpuPool};
 use hyper;
 use futures;
 use futures::{Async, BoxFuture, Future, Poll, Stream};
 
+use compression::Encoder;
+use limits::Permit;
 use msgs;
-use channels::{ChannelLayer, ReplyPump};
+use slow_clients::RequestGuard;
+use channels::{ChannelLayer, ReplyChannelGuard};
 
 
 pub enum BodyStream<C>
     where C: ChannelLayer
 {
+    Empty,
     Error(ErrorBodyStream),
+    File(FileBodyStream),
     Response(ResponseBodyStream<C>),
 }
 
 impl<C> BodyStream<C>
     where C: ChannelLayer
 {
-    pub fn response(pump: ReplyPump<C>,
-                    channel: String,
-                    initial_chunk: msgs::http::ResponseBodyChunk)
+    /// Streams the response's chunks as we receive them, compressing them with the encoder if
+    /// we've been given one. The in-flight slot is held until the last chunk arrives, and the
+    /// request guard until we've yielded it. If we're dropped before then, the pump stops
+    /// listening on the reply channel. Trailers are accepted on the last chunk, but dropped, as
+    /// Hyper has no way to send them yet - accepts_trailers says whether the client sent
+    /// `TE: trailers`, so that we only complain about the ones it asked for. The request ID is
+    /// for logging.
+    pub fn response(reply_channel: ReplyChannelGuard<C>,
+                    initial_chunk: msgs::http::ResponseBodyChunk,
+                    encoder: Option<Encoder>,
+                    request: Option<RequestGuard>,
+                    in_flight: Permit,
+                    accepts_trailers: bool,
+                    request_id: String)
                     -> Self {
         BodyStream::Response(ResponseBodyStream {
-            pump: pump,
-            channel: channel,
+            reply_channel: reply_channel,
             future: Some(futures::future::ok(initial_chunk).boxed()),
+            encoder: encoder,
+            request: request,
+            in_flight: Some(in_flight),
+            accepts_trailers: accepts_trailers,
+            request_id: request_id,
         })
     }
 
     pub fn error(body: String) -> Self {
         BodyStream::Error(ErrorBodyStream(Some(body)))
     }
+
+    pub fn empty() -> Self {
+        BodyStream::Empty
+    }
+
+    /// Streams len bytes from the file's current position, reading them on the thread-pool. The
+    /// request guard is held until they've all been read.
+    pub fn file(file: File, len: u64, cpu_pool: CpuPool, request: Option<RequestGuard>) -> Self {
+        BodyStream::File(FileBodyStream {
+            cpu_pool: cpu_pool,
+            file: Some(file),
+            remaining: len,
+            future: None,
+            request: request,
+        })
+    }
 }
 
 impl<C> Stream for BodyStream<C>
@@ -42,7 +81,9 @@ impl<C> Stream for BodyStream<C>
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         match self {
+            &mut BodyStream::Empty => Ok(Async::Ready(None)),
             &mut BodyStream::Error(ref mut resp) => resp.poll(),
+            &mut BodyStream::File(ref mut resp) => resp.poll(),
             &mut BodyStream::Response(ref mut resp) => resp.poll(),
         }
     }
@@ -64,12 +105,79 @@ impl Stream for ErrorBodyStream {
 }
 
 
+// How much of a file we read in one go.
+const FILE_CHUNK_SIZE: u64 = 64 * 1024;
+
+pub struct FileBodyStream {
+    cpu_pool: CpuPool,
+    // We hand the file over to the thread-pool whilst we're reading from it.
+    file: Option<File>,
+    remaining: u64,
+    future: Option<CpuFuture<(File, Vec<u8>), std::io::Error>>,
+    // Keeps the connection from counting as idle while we're still sending the file.
+    request: Option<RequestGuard>,
+}
+
+impl Stream for FileBodyStream {
+    type Item = Vec<u8>;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match std::mem::replace(&mut self.future, None) {
+            // We're waiting for a chunk to be read - poll the future.
+            Some(mut future) => {
+                match future.poll() {
+                    Ok(Async::Ready((file, chunk))) => {
+                        // The file has been truncated since we started sending it. We've already
+                        // promised the client more than this, so all we can do is give up.
+                        if chunk.is_empty() {
+                            return Err(hyper::Error::Incomplete);
+                        }
+                        self.file = Some(file);
+                        self.remaining -= chunk.len() as u64;
+                        Ok(Async::Ready(Some(chunk)))
+                    }
+                    Ok(Async::NotReady) => {
+                        self.future = Some(future);
+                        Ok(Async::NotReady)
+                    }
+                    Err(err) => Err(hyper::Error::Io(err)),
+                }
+            }
+            None if self.remaining == 0 => {
+                self.request = None;
+                Ok(Async::Ready(None))
+            }
+            // Start reading the next chunk.
+            None => {
+                let mut file = self.file.take().expect("File missing from FileBodyStream");
+                let len = std::cmp::min(self.remaining, FILE_CHUNK_SIZE) as usize;
+                self.future = Some(self.cpu_pool.spawn_fn(move || {
+                    let mut chunk = vec![0; len];
+                    let read = file.read(&mut chunk)?;
+                    chunk.truncate(read);
+                    Ok((file, chunk))
+                }));
+                self.poll()
+            }
+        }
+    }
+}
+
+
 pub struct ResponseBodyStream<C>
     where C: ChannelLayer
 {
-    pump: ReplyPump<C>,
-    channel: String,
+    reply_channel: ReplyChannelGuard<C>,
     future: Option<BoxFuture<msgs::http::ResponseBodyChunk, ()>>,
+    encoder: Option<Encoder>,
+    // Keeps the connection from counting as idle while we're still streaming the response.
+    request: Option<RequestGuard>,
+    // Counts the request as in flight until the application has sent all of the response.
+    in_flight: Option<Permit>,
+    // Whether the client sent `TE: trailers`.
+    accepts_trailers: bool,
+    request_id: String,
 }
 
 impl<C> Stream for ResponseBodyStream<C>
@@ -86,24 +194,79 @@ impl<C> Stream for ResponseBodyStream<C>
                     // We have a chunk. If it isn't our last, then start waiting for the next chunk,
                     // whilst we yield this one.
                     Ok(Async::Ready(resp)) => {
+                        if !resp.trailers.is_empty() {
+                            self.drop_trailers(resp.more_content);
+                        }
                         self.future = match resp.more_content {
-                            true => Some(self.pump.wait_for_reply_async(self.channel.clone())),
-                            false => None,
+                            true => {
+                                let channel = self.reply_channel.channel().to_owned();
+                                Some(self.reply_channel
+                                    .pump()
+                                    .wait_for_reply_async(channel, self.request_id.clone()))
+                            }
+                            // The application is done with the request.
+                            false => {
+                                self.reply_channel.finish();
+                                self.in_flight = None;
+                                None
+                            }
                         };
-                        // Yield the chunk we've received.
-                        Ok(Async::Ready(Some(resp.content.into())))
+                        // Yield the chunk we've received, compressing it if necessary.
+                        let content: Vec<u8> = resp.content.into();
+                        match self.encoder.take() {
+                            Some(mut encoder) => {
+                                let mut compressed = encoder.compress(&content)
+                                    .map_err(hyper::Error::Io)?;
+                                match resp.more_content {
+                                    true => self.encoder = Some(encoder),
+                                    false => {
+                                        compressed.extend(encoder.finish()
+                                            .map_err(hyper::Error::Io)?)
+                                    }
+                                }
+                                Ok(Async::Ready(Some(compressed)))
+                            }
+                            None => Ok(Async::Ready(Some(content))),
+                        }
                     }
                     // Our future isn't ready - remember to poll it next time.
                     Ok(Async::NotReady) => {
                         self.future = Some(future);
                         Ok(Async::NotReady)
                     }
-                    // XXX What error should we be sending here?
-                    Err(()) => Err(hyper::Error::Incomplete),
+                    // The pump has already logged why. We've sent the client part of the response,
+                    // so all we can do is cut it short.
+                    Err(()) => {
+                        println!("Cutting short the response to request {} on {}",
+                                 self.request_id,
+                                 self.reply_channel.channel());
+                        Err(hyper::Error::Incomplete)
+                    }
                 }
             }
-            // The last chunk was our last. Indicate end-of-stream.
-            None => Ok(Async::Ready(None)),
+            // The last chunk was our last. Indicate end-of-stream, and let the connection go
+            // idle once Hyper has finished writing it out.
+            None => {
+                self.request = None;
+                Ok(Async::Ready(None))
+            }
         }
     }
 }
+
+impl<C> ResponseBodyStream<C>
+    where C: ChannelLayer
+{
+    // Hyper always ends a chunked body itself, with no way for us to add a trailer section, so
+    // all we can do with trailers is say that we've dropped them.
+    fn drop_trailers(&self, more_content: bool) {
+        let reason = match (more_content, self.accepts_trailers) {
+            (true, _) => "they were sent before the last chunk",
+            (false, true) => "we can't send trailers yet",
+            (false, false) => "the client didn't send TE: trailers",
+        };
+        println!("Dropping trailers from the response to request {}: {}",
+                 self.request_id,
+                 reason);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// When to stop sending requests to an application whose workers have stopped responding.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerOptions {
+    /// How many of the most recent requests to consider.
+    pub window: usize,
+    /// Trip once at least this fraction of them have timed out.
+    pub failure_ratio: f64,
+    /// How long to refuse requests for before trying the application again.
+    pub cooldown: Duration,
+    /// HTML to serve while the breaker is open, instead of our usual error page.
+    pub maintenance_page: Option<String>,
+}
+
+
+/// How a request that was let through the breaker turned out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Responded,
+    TimedOut,
+    /// The request failed for some other reason, which tells us nothing about the workers.
+    Abandoned,
+}
+
+
+enum State {
+    /// Requests are let through, and we remember whether each timed out.
+    Closed(VecDeque<bool>),
+    /// Requests are refused until the cooldown is over.
+    Open(Instant),
+    /// The cooldown is over, and a single probe request is let through to see if the workers
+    /// have recovered. Holds the current probe's number and when it was let through, if there is
+    /// one.
+    HalfOpen(Option<(usize, Instant)>),
+}
+
+
+/// Given for each request let through the breaker, to be handed back with its outcome. Only the
+/// outcome of the current probe decides whether a half-open breaker closes.
+#[derive(Debug)]
+pub struct Ticket(Option<usize>);
+
+
+/// Refuses requests for a while once too many recent requests have timed out, so that we don't
+/// fill the channel layer with requests that nobody is going to answer.
+pub struct CircuitBreaker {
+    options: CircuitBreakerOptions,
+    state: Mutex<State>,
+    probes: AtomicUsize,
+}
+
+impl CircuitBreaker {
+    pub fn new(options: CircuitBreakerOptions) -> Self {
+        CircuitBreaker {
+            options: options,
+            state: Mutex::new(State::Closed(VecDeque::new())),
+            probes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn options(&self) -> &CircuitBreakerOptions {
+        &self.options
+    }
+
+    /// Whether a request may be sent to the application. If not, returns how long the client
+    /// should wait before retrying. Every request let through must have its outcome recorded
+    /// with the ticket it was given.
+    pub fn check(&self) -> Result<Ticket, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed(_) => return Ok(Ticket(None)),
+            State::Open(until) if now < until => return Err(until - now),
+            // A probe whose outcome we never heard about (e.g. because its client went away)
+            // mustn't keep the breaker half-open forever.
+            State::HalfOpen(Some((_, started))) if now < started + self.options.cooldown => {
+                return Err(started + self.options.cooldown - now)
+            }
+            State::Open(_) |
+            State::HalfOpen(_) => {}
+        }
+        let probe = self.probes.fetch_add(1, Ordering::Relaxed);
+        *state = State::HalfOpen(Some((probe, now)));
+        Ok(Ticket(Some(probe)))
+    }
+
+    pub fn record(&self, ticket: Ticket, outcome: Outcome) {
+        let mut state = self.state.lock().unwrap();
+        let is_probe = match *state {
+            State::HalfOpen(Some((probe, _))) => ticket.0 == Some(probe),
+            _ => false,
+        };
+        let next = match (&mut *state, outcome) {
+            (&mut State::Closed(ref mut outcomes), Outcome::Responded) |
+            (&mut State::Closed(ref mut outcomes), Outcome::TimedOut) => {
+                outcomes.push_back(outcome == Outcome::TimedOut);
+                while outcomes.len() > self.options.window {
+                    outcomes.pop_front();
+                }
+                let timed_out = outcomes.iter().filter(|timed_out| **timed_out).count();
+                match outcomes.len() == self.options.window &&
+                      timed_out as f64 >= self.options.failure_ratio * outcomes.len() as f64 {
+                    true => {
+                        println!("Circuit breaker tripped: {} of the last {} requests timed out",
+                                 timed_out,
+                                 outcomes.len());
+                        State::Open(Instant::now() + self.options.cooldown)
+                    }
+                    false => return,
+                }
+            }
+            (&mut State::HalfOpen(_), Outcome::Responded) if is_probe => {
+                println!("Circuit breaker closed: the application is responding again");
+                State::Closed(VecDeque::new())
+            }
+            (&mut State::HalfOpen(_), Outcome::TimedOut) if is_probe => {
+                State::Open(Instant::now() + self.options.cooldown)
+            }
+            (&mut State::HalfOpen(_), Outcome::Abandoned) if is_probe => State::HalfOpen(None),
+            // Requests let through before we tripped may finish while we're open or half-open,
+            // as may probes we've given up waiting for.
+            _ => return,
+        };
+        *state = next;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitBreakerOptions, Outcome, State, Ticket};
+
+    use std::time::{Duration, Instant};
+
+    fn breaker(cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerOptions {
+            window: 4,
+            failure_ratio: 0.5,
+            cooldown: cooldown,
+            maintenance_page: None,
+        })
+    }
+
+    #[test]
+    fn test_trips_on_timeouts() {
+        let breaker = breaker(Duration::from_secs(3600));
+        breaker.record(Ticket(None), Outcome::TimedOut);
+        breaker.record(Ticket(None), Outcome::TimedOut);
+        breaker.record(Ticket(None), Outcome::Responded);
+        // Not enough requests yet to judge.
+        assert!(breaker.check().is_ok());
+        breaker.record(Ticket(None), Outcome::Responded);
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn test_stays_closed() {
+        let breaker = breaker(Duration::from_secs(3600));
+        for _ in 0..10 {
+            breaker.record(Ticket(None), Outcome::Responded);
+            breaker.record(Ticket(None), Outcome::Abandoned);
+        }
+        breaker.record(Ticket(None), Outcome::TimedOut);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_half_open_waits_for_probe() {
+        let breaker = breaker(Duration::from_secs(3600));
+        *breaker.state.lock().unwrap() = State::Open(Instant::now());
+        let probe = breaker.check().unwrap();
+        assert!(breaker.check().is_err());
+        breaker.record(probe, Outcome::Abandoned);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breaker = breaker(Duration::from_millis(0));
+        for _ in 0..4 {
+            breaker.record(Ticket(None), Outcome::TimedOut);
+        }
+        // Without a cooldown, we half-open straight away and let a probe through.
+        let probe = breaker.check().unwrap();
+        breaker.record(probe, Outcome::TimedOut);
+        let probe = breaker.check().unwrap();
+        breaker.record(probe, Outcome::Responded);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_half_open_ignores_stragglers() {
+        let breaker = breaker(Duration::from_millis(0));
+        for _ in 0..4 {
+            breaker.record(Ticket(None), Outcome::TimedOut);
+        }
+        let stale_probe = breaker.check().unwrap();
+        let probe = breaker.check().unwrap();
+        // Neither a request from before we tripped, nor a probe we've since replaced, gets to
+        // close the breaker.
+        breaker.record(Ticket(None), Outcome::Responded);
+        breaker.record(stale_probe, Outcome::Responded);
+        match *breaker.state.lock().unwrap() {
+            State::HalfOpen(Some(_)) => {}
+            _ => panic!("Breaker should still be waiting for its probe"),
+        }
+        breaker.record(probe, Outcome::TimedOut);
+        match *breaker.state.lock().unwrap() {
+            State::Open(_) => {}
+            _ => panic!("Breaker should have re-opened"),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std;
 use std::ascii::AsciiExt;
 use std::error::Error;
+use std::time::Duration;
 
 use r2d2;
 use rand::{thread_rng, Rng};
@@ -9,7 +10,7 @@ use serde::{Deserialize, Serialize};
 pub mod redis;
 pub mod reply_pump;
 pub use self::redis::{RedisChannelLayer, RedisChannelLayerManager};
-pub use self::reply_pump::ReplyPump;
+pub use self::reply_pump::{ReplyChannelGuard, ReplyPump};
 
 
 fn random_string(n: usize) -> String {
@@ -22,14 +23,15 @@ fn shuffle<T>(values: &mut [T]) {
 
 fn is_valid_channel_name(name: &str) -> bool {
     let too_long = name.len() >= 100;
-    let invalid_chars = name.chars().all(|c| {
+    let valid_chars = name.chars().all(|c| {
         c.is_ascii() &&
         (c.is_alphanumeric() || ".-_?!".contains(c))
     });
-    too_long || invalid_chars
+    !too_long && valid_chars
 }
 
-fn validate_channel_name(name: &str) -> Result<(), ChannelError> {
+/// Checks that a channel name is one we can send on, e.g. before accepting it in our options.
+pub fn validate_channel_name(name: &str) -> Result<(), ChannelError> {
     if is_valid_channel_name(name) {
         Ok(())
     } else {
@@ -37,6 +39,16 @@ fn validate_channel_name(name: &str) -> Result<(), ChannelError> {
     }
 }
 
+/// Process-local channels (e.g. http.response.abc123!def456) are received on their non-local
+/// part, which is everything up to and including the !. Messages for all of the channels sharing
+/// a non-local part are routed locally by the process receiving them.
+fn non_local_name(name: &str) -> &str {
+    match name.find('!') {
+        Some(index) => &name[..index + 1],
+        None => name,
+    }
+}
+
 
 pub struct ChannelReply {
     pub buf: Vec<u8>,
@@ -56,6 +68,22 @@ pub trait ChannelLayer
         where I: Iterator<Item = &'a String> + Clone;
     fn deserialize<D: Deserialize>(reply: ChannelReply) -> Result<D, ChannelError>;
     fn new_channel(&self, pattern: &str) -> Result<String, ChannelError>;
+
+    /// The number of messages waiting to be received on a channel. Layers which can't tell us
+    /// report that every channel is empty.
+    fn queue_length(&self, _channel: &str) -> Result<usize, ChannelError> {
+        Ok(0)
+    }
+
+    /// Takes a token from each of the rate limit buckets, given as (name, rate, burst), if they
+    /// all have one. The buckets are shared by everyone using the layer, and each refills at
+    /// `rate` tokens per second up to `burst`. If any are empty, takes nothing and returns how
+    /// long until they won't be. Layers which can't share buckets always have tokens to give.
+    fn take_tokens(&self,
+                   _buckets: &[(String, f64, f64)])
+                   -> Result<Option<Duration>, ChannelError> {
+        Ok(None)
+    }
 }
 
 
@@ -115,7 +143,7 @@ impl Error for ChannelError {
 #[cfg(test)]
 mod tests {
     use super::{
-        is_valid_channel_name, validate_channel_name,
+        is_valid_channel_name, non_local_name, validate_channel_name,
     };
 
     #[test]
@@ -125,13 +153,27 @@ mod tests {
         assert_eq!(is_valid_channel_name("http.response.body?aS45543"), true);
         assert_eq!(is_valid_channel_name("aA1!?-_."), true);
 
+        assert_eq!(is_valid_channel_name(&"a".repeat(99)), true);
+
         assert_eq!(is_valid_channel_name("@"), false);
         assert_eq!(is_valid_channel_name("☃"), false);
+        assert_eq!(is_valid_channel_name(&"a".repeat(100)), false);
+        assert_eq!(is_valid_channel_name(&"@".repeat(100)), false);
     }
 
     #[test]
     fn test_validate_channel_name() {
         assert!(validate_channel_name("http.request").is_ok());
         assert!(validate_channel_name("@").is_err());
+        assert!(validate_channel_name(&"a".repeat(100)).is_err());
+        assert!(validate_channel_name(&format!("http.request{}", "@".repeat(100))).is_err());
+    }
+
+    #[test]
+    fn test_non_local_name() {
+        assert_eq!(non_local_name("http.request"), "http.request");
+        assert_eq!(non_local_name("http.response.abc!"), "http.response.abc!");
+        assert_eq!(non_local_name("http.response.abc!aS45543"), "http.response.abc!");
+        assert_eq!(non_local_name("http.response.body?aS45543"), "http.response.body?aS45543");
     }
 }
@@ -4,15 +4,63 @@ use std::time::Duration;
 use r2d2;
 use redis;
 use redis::{ConnectionInfo, Commands, IntoConnectionInfo};
+use ring::digest;
 use rmp_serde::encode::VariantWriter;
 use rmp;
 use rmp::Marker;
+use rmp::decode::ValueReadError;
 use rmp::encode::ValueWriteError;
 use rmp_serde;
 use serde;
 use serde::{Deserialize, Serialize};
 
-use super::{random_string, shuffle, validate_channel_name, ChannelError, ChannelLayer, ChannelReply};
+use super::{non_local_name, random_string, shuffle, validate_channel_name, ChannelError,
+            ChannelLayer, ChannelReply};
+
+
+/// Where asgi_redis looks for Redis unless told otherwise.
+pub const DEFAULT_URL: &'static str = "redis://127.0.0.1";
+
+/// The prefix asgi_redis puts on its keys unless told otherwise.
+pub const DEFAULT_PREFIX: &'static str = "asgi:";
+
+// Messages sent to a process-local channel are pushed on to the channel's non-local name, with
+// the full name of the channel stored in the message under this key. This is what asgi_redis does.
+const LOCAL_CHANNEL_KEY: &'static str = "__asgi_channel__";
+
+
+// Refills rate limit buckets stored in Redis hashes, and takes a token from each if they all have
+// one. Uses Redis' clock so that every server instance agrees on the time. ARGV holds each
+// bucket's rate and burst in turn. Returns how many seconds to wait, as a string to keep the
+// fraction.
+const TAKE_TOKENS_SCRIPT: &'static str = r"
+    redis.replicate_commands()
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+    local tokens = {}
+    local wait = 0
+    for i = 1, #KEYS do
+        local rate = tonumber(ARGV[i * 2 - 1])
+        local burst = tonumber(ARGV[i * 2])
+        local bucket = redis.call('HMGET', KEYS[i], 'tokens', 'updated')
+        local updated = tonumber(bucket[2]) or now
+        local refilled = (tonumber(bucket[1]) or burst) + math.max(0, now - updated) * rate
+        tokens[i] = math.min(burst, refilled)
+        if tokens[i] < 1 then
+            wait = math.max(wait, (1 - tokens[i]) / rate)
+        end
+    end
+    for i = 1, #KEYS do
+        local rate = tonumber(ARGV[i * 2 - 1])
+        local burst = tonumber(ARGV[i * 2])
+        if wait == 0 then
+            tokens[i] = tokens[i] - 1
+        end
+        redis.call('HMSET', KEYS[i], 'tokens', tokens[i], 'updated', now)
+        redis.call('EXPIRE', KEYS[i], math.ceil(burst / rate) + 1)
+    end
+    return tostring(wait)
+";
 
 
 // asgi_redis expects msgpack map objects, which it'll deserialize to Python dicts.
@@ -46,6 +94,27 @@ fn msgpack_serialize<S: Serialize>(val: &S) -> Result<Vec<u8>, rmp_serde::encode
     Ok(buf)
 }
 
+// Adds a string field to an already serialized msgpack map. We use this to tag messages with the
+// process-local channel they're destined for, without needing to know the type of the message.
+fn msgpack_add_field(buf: &[u8], key: &str, value: &str) -> Result<Vec<u8>, ChannelError> {
+    let mut rd = buf;
+    let len = rmp::decode::read_map_len(&mut rd)?;
+
+    let mut new_buf = Vec::with_capacity(buf.len() + key.len() + value.len() + 10);
+    rmp::encode::write_map_len(&mut new_buf, len + 1)?;
+    rmp::encode::write_str(&mut new_buf, key)?;
+    rmp::encode::write_str(&mut new_buf, value)?;
+    new_buf.extend_from_slice(rd);
+    Ok(new_buf)
+}
+
+// Just enough of a message to find out which process-local channel it was sent on.
+#[derive(Deserialize)]
+struct LocalChannelMessage {
+    #[serde(rename = "__asgi_channel__")]
+    channel: String,
+}
+
 fn msgpack_deserialize<D: Deserialize>(buf: &[u8]) -> Result<D, rmp_serde::decode::Error> {
     // We don't have to do anything fancy here - rmp_serde will convert a msgpack map to
     // a Rust strut just fine.
@@ -53,6 +122,17 @@ fn msgpack_deserialize<D: Deserialize>(buf: &[u8]) -> Result<D, rmp_serde::decod
     serde::Deserialize::deserialize(&mut deserializer)
 }
 
+// Rate limit buckets may be named after secrets, e.g. API keys, so we name their keys after a
+// digest of the bucket's name instead. This also keeps the keys the same length however long the
+// name is.
+fn bucket_digest(bucket: &str) -> String {
+    digest::digest(&digest::SHA256, bucket.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 
 pub struct RedisChannelLayer {
     conn: redis::Connection,
@@ -62,11 +142,20 @@ pub struct RedisChannelLayer {
     blpop_timeout: Duration,
 
     lpopmany: redis::Script,
+    take_tokens: redis::Script,
 }
 
 impl RedisChannelLayer {
     pub fn new<I>(info: I) -> Result<Self, ChannelError>
         where I: IntoConnectionInfo
+    {
+        RedisChannelLayer::with_prefix(info, DEFAULT_PREFIX)
+    }
+
+    /// Creates a channel layer whose keys all begin with the prefix, so that several
+    /// applications can share a Redis database. This must match the application's prefix.
+    pub fn with_prefix<I>(info: I, prefix: &str) -> Result<Self, ChannelError>
+        where I: IntoConnectionInfo
     {
         let client = redis::Client::open(info)?;
         let conn = client.get_connection()?;
@@ -85,11 +174,12 @@ impl RedisChannelLayer {
         Ok(RedisChannelLayer {
             conn: conn,
 
-            prefix: "asgi:".to_owned(),
+            prefix: prefix.to_owned(),
             expiry: Duration::from_secs(60),
             blpop_timeout: Duration::from_secs(5),
 
             lpopmany: lpopmany,
+            take_tokens: redis::Script::new(TAKE_TOKENS_SCRIPT),
         })
     }
 }
@@ -101,9 +191,12 @@ impl ChannelLayer for RedisChannelLayer {
         validate_channel_name(channel)?;
 
         let message_key = self.prefix.to_owned() + "msg:" + &random_string(10);
-        let channel_key = self.prefix.to_owned() + channel;
+        let channel_key = self.prefix.to_owned() + non_local_name(channel);
 
-        let buf = msgpack_serialize(msg).unwrap();
+        let mut buf = msgpack_serialize(msg).unwrap();
+        if channel != non_local_name(channel) {
+            buf = msgpack_add_field(&buf, LOCAL_CHANNEL_KEY, channel)?;
+        }
 
         let message_expiry = self.expiry.as_secs() as usize;
         let channel_expiry = (self.expiry.as_secs() + 1) as usize;
@@ -161,7 +254,13 @@ impl ChannelLayer for RedisChannelLayer {
                     match message {
                         Some(buf) => {
                             // Remove prefix from returned channel name.
-                            let channel_name = channel_name[self.prefix.len()..].to_owned();
+                            let mut channel_name = channel_name[self.prefix.len()..].to_owned();
+                            // If we received on a process-local channel, the message tells us
+                            // which of its channels it was actually sent to.
+                            if channel_name.ends_with("!") {
+                                let local: LocalChannelMessage = msgpack_deserialize(&buf)?;
+                                channel_name = local.channel;
+                            }
                             let reply = ChannelReply { buf: buf };
                             return Ok(Some((channel_name, reply)));
                         }
@@ -184,23 +283,61 @@ impl ChannelLayer for RedisChannelLayer {
         if !pattern.ends_with("!") && !pattern.ends_with("?") {
             return Err(ChannelError::InvalidChannelName);
         }
+        // A process-local channel may only have a single !, separating its non-local and local
+        // parts.
+        if pattern.ends_with("!") && non_local_name(pattern) != pattern {
+            return Err(ChannelError::InvalidChannelName);
+        }
 
         // TODO: Check the new channel doesn't already exist.
         Ok(pattern.to_owned() + &random_string(10))
     }
+
+    fn queue_length(&self, channel: &str) -> Result<usize, ChannelError> {
+        validate_channel_name(channel)?;
+        Ok(self.conn.llen(self.prefix.to_owned() + non_local_name(channel))?)
+    }
+
+    fn take_tokens(&self,
+                   buckets: &[(String, f64, f64)])
+                   -> Result<Option<Duration>, ChannelError> {
+        let mut invocation = self.take_tokens.prepare_invoke();
+        for &(ref bucket, rate, burst) in buckets {
+            // Channel names can't contain a colon, so this can't clash with a channel.
+            invocation.key(self.prefix.to_owned() + "ratelimit:" + &bucket_digest(bucket))
+                .arg(rate)
+                .arg(burst);
+        }
+        let wait: String = invocation.invoke(&self.conn)?;
+        let wait: f64 = wait.parse().map_err(|err| ChannelError::Deserialize(Box::new(err)))?;
+        match wait > 0.0 {
+            true => Ok(Some(Duration::new(wait.trunc() as u64, (wait.fract() * 1e9) as u32))),
+            false => Ok(None),
+        }
+    }
 }
 
 
 #[derive(Debug)]
 pub struct RedisChannelLayerManager {
     info: ConnectionInfo,
+    prefix: String,
 }
 
 impl RedisChannelLayerManager {
     pub fn new<I>(info: I) -> Result<Self, ChannelError>
         where I: IntoConnectionInfo
     {
-        Ok(RedisChannelLayerManager { info: info.into_connection_info()? })
+        RedisChannelLayerManager::with_prefix(info, DEFAULT_PREFIX)
+    }
+
+    pub fn with_prefix<I>(info: I, prefix: &str) -> Result<Self, ChannelError>
+        where I: IntoConnectionInfo
+    {
+        Ok(RedisChannelLayerManager {
+            info: info.into_connection_info()?,
+            prefix: prefix.to_owned(),
+        })
     }
 }
 
@@ -209,7 +346,7 @@ impl r2d2::ManageConnection for RedisChannelLayerManager {
     type Error = ChannelError;
 
     fn connect(&self) -> Result<Self::Connection, ChannelError> {
-        Ok(RedisChannelLayer::new(self.info.clone())?)
+        Ok(RedisChannelLayer::with_prefix(self.info.clone(), &self.prefix)?)
     }
 
     fn is_valid(&self, channel_layer: &mut Self::Connection) -> Result<(), ChannelError> {
@@ -237,8 +374,61 @@ impl From<rmp_serde::encode::Error> for ChannelError {
     }
 }
 
+impl From<ValueWriteError> for ChannelError {
+    fn from(err: ValueWriteError) -> ChannelError {
+        ChannelError::Serialize(Box::new(err))
+    }
+}
+
+impl From<ValueReadError> for ChannelError {
+    fn from(err: ValueReadError) -> ChannelError {
+        ChannelError::Deserialize(Box::new(err))
+    }
+}
+
 impl From<rmp_serde::decode::Error> for ChannelError {
     fn from(err: rmp_serde::decode::Error) -> ChannelError {
         ChannelError::Deserialize(Box::new(err))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_digest, msgpack_add_field, msgpack_deserialize, msgpack_serialize,
+                LocalChannelMessage};
+
+    #[derive(Serialize)]
+    struct Message {
+        content: &'static str,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct TaggedMessage {
+        content: String,
+        #[serde(rename = "__asgi_channel__")]
+        channel: String,
+    }
+
+    #[test]
+    fn test_msgpack_add_field() {
+        let buf = msgpack_serialize(&Message { content: "hello" }).unwrap();
+        let buf = msgpack_add_field(&buf, "__asgi_channel__", "http.response.abc!def").unwrap();
+
+        let message: TaggedMessage = msgpack_deserialize(&buf).unwrap();
+        assert_eq!(message,
+                   TaggedMessage {
+                       content: "hello".to_owned(),
+                       channel: "http.response.abc!def".to_owned(),
+                   });
+
+        let local: LocalChannelMessage = msgpack_deserialize(&buf).unwrap();
+        assert_eq!(local.channel, "http.response.abc!def");
+    }
+
+    #[test]
+    fn test_bucket_digest() {
+        assert_eq!(bucket_digest("0:secret"),
+                   "3011e3718652864c2c88c6454bf04d9b0d10137fc470e04073dd4a2844298566");
+    }
+}
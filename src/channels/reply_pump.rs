@@ -1,16 +1,25 @@
 use std;
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crossbeam::sync::MsQueue;
 use futures::{BoxFuture, Future};
 use futures::sync::oneshot;
 
+use rand::{thread_rng, Rng};
 use serde::Deserialize;
 
-use channels::{ChannelLayer, ChannelReply};
+use channels::{non_local_name, random_string, ChannelError, ChannelLayer, ChannelReply};
+
+
+// How long we hold on to a reply for a channel nobody is waiting on yet. This matches the expiry
+// of messages in asgi_redis - anything older would have expired had it not been received.
+const PENDING_REPLY_EXPIRY_SECS: u64 = 60;
 
 
 // A reply channel that we should be listening on. We complete the provided sender once we have
@@ -24,13 +33,27 @@ struct ReplyChannel {
 // These requests are sent to the Reply Pump's thread via a queue, to ask it to do things.
 enum PumpRequest {
     Listen(ReplyChannel),
+    // Stop listening on a channel, dropping any replies we've already received on it.
+    Forget(String),
     Join,
 }
 
 
-// Shared context between the Reply Pump and its thread.
+// Shared context between the Reply Pump and one of its worker threads.
 struct PumpContext {
     queue: MsQueue<PumpRequest>,
+    // The process-local channel (ending in !) that this worker receives all of its replies on.
+    local_channel: String,
+}
+
+
+// Replies we've received on a process-local channel before anyone has asked to wait for them.
+// This happens when a worker replies before we've registered interest in the reply channel, or
+// when a streaming response's chunks arrive faster than we consume them.
+struct PendingReplies {
+    // When the last of the replies arrived.
+    received: Instant,
+    replies: VecDeque<ChannelReply>,
 }
 
 
@@ -41,10 +64,14 @@ struct SyncPhantomData<T>(PhantomData<T>);
 unsafe impl<T> Sync for SyncPhantomData<T> {}
 
 
+// The Reply Pump is made up of one or more worker threads, each with its own channel layer. Each
+// worker owns a process-local channel, and reply channels created with new_reply_channel() are
+// routed to the worker owning their local part. Any other reply channel is assigned to a worker by
+// hashing its name, so that every chunk of a streaming response is waited on by the same worker.
 pub struct ReplyPump<C>
     where C: 'static + ChannelLayer + Send
 {
-    context: Arc<PumpContext>,
+    contexts: Arc<Vec<Arc<PumpContext>>>,
     phantom: SyncPhantomData<C>,
 }
 
@@ -53,7 +80,7 @@ impl<C> Clone for ReplyPump<C>
 {
     fn clone(&self) -> Self {
         return ReplyPump {
-            context: self.context.clone(),
+            contexts: self.contexts.clone(),
             phantom: SyncPhantomData(PhantomData),
         };
     }
@@ -62,41 +89,88 @@ impl<C> Clone for ReplyPump<C>
 impl<C> ReplyPump<C>
     where C: 'static + ChannelLayer + Send
 {
-    pub fn new(channel_layer: C) -> Self {
-        let reply_pump = ReplyPump {
-            context: Arc::new(PumpContext { queue: MsQueue::new() }),
+    /// Creates a Reply Pump with one worker thread for each of the channel layers given.
+    pub fn new(channel_layers: Vec<C>) -> Self {
+        assert!(!channel_layers.is_empty(), "ReplyPump requires at least one channel layer");
+
+        let contexts = channel_layers.into_iter()
+            .map(|channel_layer| {
+                let context = Arc::new(PumpContext {
+                    queue: MsQueue::new(),
+                    local_channel: format!("http.response.{}!", random_string(10)),
+                });
+                let thread_context = context.clone();
+                std::thread::spawn(move || Self::thread_func(&thread_context, channel_layer));
+                context
+            })
+            .collect();
+
+        ReplyPump {
+            contexts: Arc::new(contexts),
             phantom: SyncPhantomData(PhantomData),
-        };
+        }
+    }
 
-        let context = reply_pump.context.clone();
-        std::thread::spawn(move || Self::thread_func(&context, channel_layer));
+    /// Creates a new process-local reply channel. Replies sent on it will be received by one of
+    /// the pump's workers, which BLPOPs a single key in Redis regardless of how many requests
+    /// are in flight.
+    pub fn new_reply_channel(&self, channel_layer: &C) -> Result<String, ChannelError> {
+        let index = thread_rng().gen_range(0, self.contexts.len());
+        channel_layer.new_channel(&self.contexts[index].local_channel)
+    }
 
-        reply_pump
+    // Picks the worker responsible for a reply channel.
+    fn context_for(&self, channel: &str) -> &PumpContext {
+        let local_channels: Vec<&str> = self.contexts
+            .iter()
+            .map(|context| context.local_channel.as_ref())
+            .collect();
+        &self.contexts[worker_for(&local_channels, channel)]
     }
 
-    pub fn wait_for_reply_async<D>(&self, channel: String) -> BoxFuture<D, ()>
-        where D: Deserialize
+    /// Waits for the next reply on the channel. Failures are logged with the ID of the request
+    /// the reply is for.
+    pub fn wait_for_reply_async<D>(&self, channel: String, request_id: String) -> BoxFuture<D, ()>
+        where D: 'static + Deserialize + Send
     {
         let (tx, rx) = oneshot::channel::<ChannelReply>();
 
-        self.context.queue.push(PumpRequest::Listen(ReplyChannel {
-            channel: channel,
+        self.context_for(&channel).queue.push(PumpRequest::Listen(ReplyChannel {
+            channel: channel.clone(),
             sender: tx,
         }));
 
-        // TODO: Propogate canceled error? We should probably wrap it in our own type first.
-        rx.map_err(|_| ())
-            .map(|reply| C::deserialize(reply).unwrap())
+        let cancelled_id = request_id.clone();
+        let cancelled_channel = channel.clone();
+        rx.map_err(move |_| {
+                println!("Stopped waiting for a reply to request {} on {}: the reply pump has \
+                          gone away",
+                         cancelled_id,
+                         cancelled_channel)
+            })
+            .and_then(move |reply| {
+                C::deserialize(reply).map_err(|err| {
+                    println!("Invalid reply to request {} on {}: {}", request_id, channel, err)
+                })
+            })
             .boxed()
     }
 
+    /// Stops waiting for replies on the channel, e.g. once we've given up on the request. Any
+    /// replies which arrive for it later are held until they expire, as if nobody had asked.
+    /// ReplyChannelGuard does this for us once we're done with a channel.
+    pub fn forget(&self, channel: &str) {
+        self.context_for(channel).queue.push(PumpRequest::Forget(channel.to_owned()));
+    }
+
     fn thread_func(ctx: &PumpContext, channel_layer: C) {
-        let mut reply_channels: HashMap<String, oneshot::Sender<ChannelReply>> = HashMap::new();
+        let mut replies = Replies::new();
+        let pending_expiry = Duration::from_secs(PENDING_REPLY_EXPIRY_SECS);
         loop {
             // Process any new requests from the outside world. If we have no channels to wait on,
             // then block until we receive a request.
             loop {
-                let request = match reply_channels.is_empty() {
+                let request = match replies.is_empty() {
                     true => ctx.queue.pop(),
                     false => {
                         match ctx.queue.try_pop() {
@@ -107,33 +181,335 @@ impl<C> ReplyPump<C>
                 };
                 match request {
                     PumpRequest::Listen(reply_channel) => {
-                        reply_channels.insert(reply_channel.channel, reply_channel.sender);
+                        replies.listen(reply_channel.channel, reply_channel.sender)
                     }
+                    PumpRequest::Forget(channel) => replies.forget(&channel),
                     PumpRequest::Join => return,
                 }
             }
 
-            let option = channel_layer.receive(reply_channels.keys(), false)
-                .expect("Failed to wait on reply");
-            match option {
-                Some((channel_name, reply)) => {
-                    match reply_channels.remove(&channel_name) {
-                        Some(sender) => sender.complete(reply),
-                        None => {
-                            panic!("Received reply for a channel we weren't listening on: {}",
-                                   &channel_name)
-                        }
-                    }
+            // Block until a reply arrives. Usually this is on our process-local channel alone, so
+            // new requests to listen on it don't need to interrupt us: their replies are held
+            // until they're asked for. Requests to listen on other channels wait until the
+            // channel layer gives up blocking.
+            //
+            // A message we can't make sense of (e.g. one missing its channel, or with a body
+            // that isn't msgpack) has already been taken off the channel, so skip it rather than
+            // stop every other request's replies.
+            let receive_channels = replies.receive_channels(&ctx.local_channel);
+            match channel_layer.receive(receive_channels.iter(), true) {
+                Ok(Some((channel_name, reply))) => {
+                    replies.deliver(channel_name, reply, Instant::now())
                 }
-                None => {
-                    // Delay a little bit if we didn't receive something, to avoid burning CPU.
-                    // We sleep 10ms because Daphne sleeps 10ms.
+                Ok(None) => {}
+                Err(err) => {
+                    println!("Failed to receive a reply on {}: {}", ctx.local_channel, err);
+                    // Don't spin if the channel layer is failing straight away.
                     std::thread::sleep(std::time::Duration::from_millis(10));
                 }
             }
 
-            // It would be nice to poll each sender in reply_channels and remove any that have been
-            // cancelled, however we can't do that outside of a Task.
+            // Forget replies that nobody has come to collect - the client has probably gone away.
+            replies.expire(Instant::now(), pending_expiry);
+
+            // It would be nice to poll each sender and remove any that have been cancelled,
+            // however we can't do that outside of a Task.
+        }
+    }
+}
+
+
+/// Forgets a reply channel when dropped, unless every reply on it has been received. This stops
+/// the pump listening for replies that nobody will collect, e.g. once the client has gone away or
+/// we've given up on the application.
+pub struct ReplyChannelGuard<C>
+    where C: 'static + ChannelLayer + Send
+{
+    pump: ReplyPump<C>,
+    channel: String,
+    finished: bool,
+}
+
+impl<C> ReplyChannelGuard<C>
+    where C: 'static + ChannelLayer + Send
+{
+    pub fn new(pump: ReplyPump<C>, channel: String) -> Self {
+        ReplyChannelGuard {
+            pump: pump,
+            channel: channel,
+            finished: false,
+        }
+    }
+
+    pub fn pump(&self) -> &ReplyPump<C> {
+        &self.pump
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Marks the last reply as received, so there's nothing to forget.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl<C> Drop for ReplyChannelGuard<C>
+    where C: 'static + ChannelLayer + Send
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            self.pump.forget(&self.channel);
+        }
+    }
+}
+
+
+// The channels a worker thread is waiting on, and the replies it has received for channels that
+// nobody is waiting on yet.
+struct Replies {
+    waiting: HashMap<String, oneshot::Sender<ChannelReply>>,
+    pending: HashMap<String, PendingReplies>,
+}
+
+impl Replies {
+    fn new() -> Self {
+        Replies {
+            waiting: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    // Waits for the next reply on the channel. We may already have received it.
+    fn listen(&mut self, channel: String, sender: oneshot::Sender<ChannelReply>) {
+        match take_pending(&mut self.pending, &channel) {
+            Some(reply) => sender.complete(reply),
+            None => {
+                self.waiting.insert(channel, sender);
+            }
+        }
+    }
+
+    fn forget(&mut self, channel: &str) {
+        self.waiting.remove(channel);
+        self.pending.remove(channel);
+    }
+
+    // Hands a reply to whoever is waiting for it, or holds on to it until somebody is.
+    fn deliver(&mut self, channel: String, reply: ChannelReply, now: Instant) {
+        match self.waiting.remove(&channel) {
+            Some(sender) => sender.complete(reply),
+            None => {
+                let pending = self.pending.entry(channel).or_insert_with(|| {
+                    PendingReplies {
+                        received: now,
+                        replies: VecDeque::new(),
+                    }
+                });
+                // Streams which are still sending chunks haven't been abandoned.
+                pending.received = now;
+                pending.replies.push_back(reply);
+            }
         }
     }
+
+    // Drops pending replies which have gone uncollected for longer than the expiry.
+    fn expire(&mut self, now: Instant, expiry: Duration) {
+        self.pending.retain(|_, pending| now.duration_since(pending.received) < expiry);
+    }
+
+    // The channels to receive on. Replies for process-local channels all arrive on the local part
+    // of the channel's name, so we always receive on our own local channel, and only need to
+    // receive on each other distinct local part once.
+    fn receive_channels(&self, local_channel: &str) -> Vec<String> {
+        let mut channels: Vec<String> = self.waiting
+            .keys()
+            .map(|channel| non_local_name(channel))
+            .filter(|&channel| channel != local_channel)
+            .collect::<HashSet<&str>>()
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        channels.push(local_channel.to_owned());
+        channels
+    }
+}
+
+
+// Takes the oldest pending reply received for a channel, if there is one.
+fn take_pending(pending: &mut HashMap<String, PendingReplies>,
+                channel: &str)
+                -> Option<ChannelReply> {
+    let (reply, now_empty) = match pending.get_mut(channel) {
+        Some(pending) => (pending.replies.pop_front(), pending.replies.is_empty()),
+        None => return None,
+    };
+    if now_empty {
+        pending.remove(channel);
+    }
+    reply
+}
+
+// Picks the worker responsible for a reply channel. Process-local channels belong to the worker
+// receiving on their local part, and any others are shared out by hashing their name.
+fn worker_for(local_channels: &[&str], channel: &str) -> usize {
+    let local_channel = non_local_name(channel);
+    match local_channels.iter().position(|&local| local == local_channel) {
+        Some(index) => index,
+        None => shard_for(channel, local_channels.len()),
+    }
+}
+
+fn shard_for(channel: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    channel.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use futures::Future;
+    use futures::sync::oneshot;
+
+    use channels::ChannelReply;
+    use super::{shard_for, take_pending, worker_for, Replies};
+
+    fn reply(buf: &[u8]) -> ChannelReply {
+        ChannelReply { buf: buf.to_vec() }
+    }
+
+    #[test]
+    fn test_shard_for_is_stable() {
+        let channel = "http.response!aS45543";
+        assert_eq!(shard_for(channel, 4), shard_for(channel, 4));
+        assert_eq!(shard_for(channel, 1), 0);
+    }
+
+    #[test]
+    fn test_shard_for_in_range() {
+        for i in 0..100 {
+            assert!(shard_for(&format!("http.response!{}", i), 3) < 3);
+        }
+    }
+
+    #[test]
+    fn test_worker_for_local_channels() {
+        let local_channels = ["http.response.abc!", "http.response.def!"];
+        assert_eq!(worker_for(&local_channels, "http.response.abc!aS45543"), 0);
+        assert_eq!(worker_for(&local_channels, "http.response.def!aS45543"), 1);
+        assert_eq!(worker_for(&local_channels, "http.response.def!"), 1);
+    }
+
+    #[test]
+    fn test_worker_for_other_channels() {
+        let local_channels = ["http.response.abc!", "http.response.def!"];
+        let channel = "http.response.ghi!aS45543";
+        assert_eq!(worker_for(&local_channels, channel), shard_for(channel, 2));
+    }
+
+    #[test]
+    fn test_receive_channels() {
+        let mut replies = Replies::new();
+        assert_eq!(replies.receive_channels("http.response.abc!"), ["http.response.abc!"]);
+
+        let (tx1, _rx1) = oneshot::channel();
+        let (tx2, _rx2) = oneshot::channel();
+        let (tx3, _rx3) = oneshot::channel();
+        replies.listen("http.response.abc!one".to_owned(), tx1);
+        replies.listen("http.response.abc!two".to_owned(), tx2);
+        replies.listen("http.response.def!one".to_owned(), tx3);
+        assert_eq!(replies.receive_channels("http.response.abc!"),
+                   ["http.response.def!", "http.response.abc!"]);
+    }
+
+    #[test]
+    fn test_deliver_to_listener() {
+        let mut replies = Replies::new();
+        let (tx, rx) = oneshot::channel();
+        replies.listen("http.response.abc!one".to_owned(), tx);
+        replies.deliver("http.response.abc!one".to_owned(), reply(b"hello"), Instant::now());
+
+        assert_eq!(rx.wait().unwrap().buf, b"hello");
+        assert!(replies.is_empty());
+        assert!(replies.pending.is_empty());
+    }
+
+    #[test]
+    fn test_deliver_before_listen() {
+        let mut replies = Replies::new();
+        let now = Instant::now();
+        replies.deliver("http.response.abc!one".to_owned(), reply(b"first"), now);
+        replies.deliver("http.response.abc!one".to_owned(), reply(b"second"), now);
+        assert!(replies.is_empty());
+
+        let (tx, rx) = oneshot::channel();
+        replies.listen("http.response.abc!one".to_owned(), tx);
+        assert_eq!(rx.wait().unwrap().buf, b"first");
+
+        let (tx, rx) = oneshot::channel();
+        replies.listen("http.response.abc!one".to_owned(), tx);
+        assert_eq!(rx.wait().unwrap().buf, b"second");
+        assert!(replies.is_empty());
+        assert!(replies.pending.is_empty());
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut replies = Replies::new();
+        let (tx, rx) = oneshot::channel();
+        replies.listen("http.response.abc!one".to_owned(), tx);
+        replies.deliver("http.response.abc!two".to_owned(), reply(b"hello"), Instant::now());
+
+        replies.forget("http.response.abc!one");
+        replies.forget("http.response.abc!two");
+        assert!(rx.wait().is_err());
+        assert!(replies.is_empty());
+        assert!(replies.pending.is_empty());
+    }
+
+    #[test]
+    fn test_take_pending() {
+        let mut replies = Replies::new();
+        let now = Instant::now();
+        replies.deliver("http.response.abc!one".to_owned(), reply(b"first"), now);
+        replies.deliver("http.response.abc!one".to_owned(), reply(b"second"), now);
+
+        let mut pending = replies.pending;
+        assert!(take_pending(&mut pending, "http.response.abc!two").is_none());
+        assert_eq!(take_pending(&mut pending, "http.response.abc!one").unwrap().buf, b"first");
+        assert!(pending.contains_key("http.response.abc!one"));
+        assert_eq!(take_pending(&mut pending, "http.response.abc!one").unwrap().buf, b"second");
+        assert!(!pending.contains_key("http.response.abc!one"));
+        assert!(take_pending(&mut HashMap::new(), "http.response.abc!one").is_none());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut replies = Replies::new();
+        let expiry = Duration::from_secs(60);
+        let start = Instant::now();
+        replies.deliver("http.response.abc!one".to_owned(), reply(b"old"), start);
+        replies.deliver("http.response.abc!two".to_owned(), reply(b"old"), start);
+        // Another chunk keeps the stream's replies alive.
+        replies.deliver("http.response.abc!two".to_owned(),
+                        reply(b"new"),
+                        start + Duration::from_secs(30));
+
+        replies.expire(start + Duration::from_secs(59), expiry);
+        assert_eq!(replies.pending.len(), 2);
+        replies.expire(start + Duration::from_secs(60), expiry);
+        assert!(!replies.pending.contains_key("http.response.abc!one"));
+        assert!(replies.pending.contains_key("http.response.abc!two"));
+        replies.expire(start + Duration::from_secs(90), expiry);
+        assert!(replies.pending.is_empty());
+    }
 }
@@ -0,0 +1,147 @@
+use std;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+
+/// A block of IP addresses, such as 10.0.0.0/8 or fd00::/8. A bare address is treated as a block
+/// containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            // Treat IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) as the IPv4 address they are,
+            // as that's how dual-stack sockets report IPv4 clients.
+            (IpAddr::V4(network), IpAddr::V6(addr)) => {
+                let octets = addr.octets();
+                let is_mapped = octets[..10].iter().all(|octet| *octet == 0) &&
+                                octets[10..12] == [0xff, 0xff];
+                is_mapped && prefix_matches(&network.octets(), &octets[12..], self.prefix_len)
+            }
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+/// Returns true if addr is contained in any of the blocks.
+pub fn contains_any(blocks: &[Cidr], addr: &IpAddr) -> bool {
+    blocks.iter().any(|block| block.contains(addr))
+}
+
+// Compares the first prefix_len bits of two addresses, given as big-endian octets.
+fn prefix_matches(network: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let whole_octets = (prefix_len / 8) as usize;
+    if network[..whole_octets] != addr[..whole_octets] {
+        return false;
+    }
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[whole_octets] & mask == addr[whole_octets] & mask
+}
+
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| CidrParseError)?;
+        let max_prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| CidrParseError)?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(CidrParseError);
+        }
+        Ok(Cidr {
+            addr: addr,
+            prefix_len: prefix_len,
+        })
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct CidrParseError;
+
+impl std::fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid CIDR block")
+    }
+}
+
+impl std::error::Error for CidrParseError {
+    fn description(&self) -> &str {
+        "Invalid CIDR block"
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{contains_any, Cidr, CidrParseError};
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("10.0.0.0/8".parse::<Cidr>().is_ok());
+        assert!("10.0.0.1".parse::<Cidr>().is_ok());
+        assert!("fd00::/8".parse::<Cidr>().is_ok());
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(CidrParseError));
+        assert_eq!("10.0.0/8".parse::<Cidr>(), Err(CidrParseError));
+        assert_eq!("10.0.0.0/x".parse::<Cidr>(), Err(CidrParseError));
+    }
+
+    #[test]
+    fn test_contains_v4() {
+        assert!(cidr("10.0.0.0/8").contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr("10.0.0.0/8").contains(&"11.1.2.3".parse().unwrap()));
+        assert!(cidr("10.0.0.1").contains(&"10.0.0.1".parse().unwrap()));
+        assert!(!cidr("10.0.0.1").contains(&"10.0.0.2".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains(&"192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_contains_v6() {
+        assert!(cidr("fd00::/8").contains(&"fd12::1".parse().unwrap()));
+        assert!(!cidr("fd00::/8").contains(&"fe80::1".parse().unwrap()));
+        assert!(!cidr("fd00::/8").contains(&"10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_contains_v4_mapped() {
+        assert!(cidr("10.0.0.0/8").contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr("10.0.0.0/8").contains(&"::10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_contains_any() {
+        let blocks = vec![cidr("10.0.0.0/8"), cidr("127.0.0.1")];
+        assert!(contains_any(&blocks, &"127.0.0.1".parse().unwrap()));
+        assert!(!contains_any(&blocks, &"127.0.0.2".parse().unwrap()));
+        assert!(!contains_any(&[], &"127.0.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,352 @@
+use std::ascii::AsciiExt;
+use std::io;
+use std::io::{Read, Write};
+
+use brotli2::write::BrotliEncoder;
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use hyper::Headers;
+
+
+/// When we compress responses from the application.
+#[derive(Clone, Debug)]
+pub struct CompressionOptions {
+    /// Responses smaller than this many bytes aren't worth compressing. Streaming responses
+    /// without a Content-Length are always compressed, as we don't know how long they'll be.
+    pub min_size: usize,
+    /// Compress responses whose Content-Type begins with one of these, e.g. "text/".
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            min_size: 1024,
+            content_types: ["text/",
+                            "application/javascript",
+                            "application/json",
+                            "application/xml",
+                            "image/svg+xml"]
+                .iter()
+                .map(|content_type| content_type.to_string())
+                .collect(),
+        }
+    }
+}
+
+
+/// A content coding we know how to produce.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The encoding's name, as used in Accept-Encoding and Content-Encoding.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The encoding the client prefers, if it'll accept any of them. Brotli wins ties, as it
+    /// compresses better.
+    pub fn negotiate(headers: &Headers) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in &[Encoding::Brotli, Encoding::Gzip] {
+            if let Some(q) = accepts_encoding(headers, encoding.name()) {
+                match best {
+                    Some((_, best_q)) if best_q >= q => {}
+                    _ => best = Some((encoding, q)),
+                }
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+
+/// Decides whether a response from the application is worth compressing, given its status,
+/// headers and the length of its initial chunk of content.
+pub fn should_compress(options: &CompressionOptions,
+                       status: u16,
+                       headers: &[(Vec<u8>, Vec<u8>)],
+                       initial_len: usize,
+                       more_content: bool)
+                       -> bool {
+    // These responses must not have a body.
+    if status == 204 || status == 304 || (status >= 100 && status < 200) {
+        return false;
+    }
+    // A partial response's Content-Range describes the uncompressed body, so compressing it would
+    // leave the client unable to put the parts together.
+    if status == 206 {
+        return false;
+    }
+
+    let header = |name: &str| {
+        headers.iter()
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|&(_, ref value)| String::from_utf8_lossy(value).into_owned())
+    };
+
+    // Don't interfere with anything the application has already encoded.
+    if header("content-encoding").is_some() {
+        return false;
+    }
+    // Nor with anything it has asked intermediaries to leave alone.
+    let no_transform = headers.iter()
+        .filter(|&&(ref key, _)| key.eq_ignore_ascii_case(b"cache-control"))
+        .flat_map(|&(_, ref value)| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .map(|directive| directive.split('=').next().unwrap_or("").trim().to_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .any(|directive| directive == "no-transform");
+    if no_transform {
+        return false;
+    }
+
+    let compressible_type = match header("content-type") {
+        Some(content_type) => {
+            let content_type = content_type.to_lowercase();
+            options.content_types.iter().any(|allowed| content_type.starts_with(allowed.as_str()))
+        }
+        None => false,
+    };
+    if !compressible_type {
+        return false;
+    }
+
+    let len = match header("content-length").and_then(|len| len.trim().parse::<usize>().ok()) {
+        Some(len) => Some(len),
+        None if !more_content => Some(initial_len),
+        None => None,
+    };
+    match len {
+        Some(len) => len >= options.min_size,
+        None => true,
+    }
+}
+
+
+/// Compresses a response body as it is streamed to the client. Each chunk is flushed through the
+/// compressor as soon as it is received, so that streaming responses still arrive promptly.
+pub enum Encoder {
+    Brotli(BrotliEncoder<Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(BrotliEncoder::new(Vec::new(), 5)),
+            Encoding::Gzip => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::Default))
+            }
+        }
+    }
+
+    /// Compresses a chunk, returning whatever compressed output is ready.
+    pub fn compress(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Encoder::Brotli(ref mut encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(encoder.get_mut().drain(..).collect())
+            }
+            Encoder::Gzip(ref mut encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(encoder.get_mut().drain(..).collect())
+            }
+        }
+    }
+
+    /// Finishes the compressed stream, returning whatever output remains.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+
+/// Why we couldn't decompress a request body.
+#[derive(Debug, PartialEq)]
+pub enum DecompressError {
+    TooLarge,
+    Invalid,
+}
+
+/// Decompresses a gzipped body, giving up as soon as it decompresses to more than limit bytes so
+/// that we can't be made to fill our memory with a zip bomb.
+pub fn gunzip(body: &[u8], limit: usize) -> Result<Vec<u8>, DecompressError> {
+    let decoder = GzDecoder::new(body).map_err(|_| DecompressError::Invalid)?;
+    let mut decompressed = Vec::new();
+    decoder.take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| DecompressError::Invalid)?;
+    match decompressed.len() > limit {
+        true => Err(DecompressError::TooLarge),
+        false => Ok(decompressed),
+    }
+}
+
+
+/// The q-value the client's Accept-Encoding gives the content coding, or None if it isn't
+/// acceptable. The encoding's own entry takes precedence over "*", wherever they are in the
+/// header.
+pub fn accepts_encoding(headers: &Headers, encoding: &str) -> Option<f32> {
+    let raw = match headers.get_raw("Accept-Encoding") {
+        Some(raw) => raw,
+        None => return None,
+    };
+    let mut exact = None;
+    let mut wildcard = None;
+    for line in raw.iter() {
+        for item in String::from_utf8_lossy(line).split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or("").trim();
+            let q = params.filter_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") { param[2..].parse::<f32>().ok() } else { None }
+                })
+                .next()
+                .unwrap_or(1.0);
+            if name.eq_ignore_ascii_case(encoding) {
+                exact = exact.or(Some(q));
+            } else if name == "*" {
+                wildcard = wildcard.or(Some(q));
+            }
+        }
+    }
+    // An encoding with a q-value of zero is explicitly not acceptable.
+    match exact.or(wildcard) {
+        Some(q) if q > 0.0 => Some(q),
+        _ => None,
+    }
+}
+
+
+/// Makes an ETag weak, as a compressed body is no longer byte-for-byte the one it was given for.
+pub fn weaken_etag(etag: Vec<u8>) -> Vec<u8> {
+    match etag.starts_with(b"W/") {
+        true => etag,
+        false => {
+            let mut weak = b"W/".to_vec();
+            weak.extend(etag);
+            weak
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{accepts_encoding, gunzip, should_compress, weaken_etag, CompressionOptions,
+                DecompressError, Encoder, Encoding};
+
+    use std::io::{Read, Write};
+
+    use flate2;
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use hyper::Headers;
+
+    use test_utils::headers;
+
+    #[test]
+    fn test_accepts_encoding() {
+        let mut headers = Headers::new();
+        assert_eq!(accepts_encoding(&headers, "gzip"), None);
+        assert_eq!(Encoding::negotiate(&headers), None);
+
+        headers.set_raw("Accept-Encoding", "gzip, deflate, br;q=0");
+        assert_eq!(accepts_encoding(&headers, "gzip"), Some(1.0));
+        assert_eq!(accepts_encoding(&headers, "GZIP"), Some(1.0));
+        assert_eq!(accepts_encoding(&headers, "br"), None);
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Gzip));
+
+        headers.set_raw("Accept-Encoding", "*");
+        assert_eq!(accepts_encoding(&headers, "br"), Some(1.0));
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Brotli));
+
+        // The encoding's own entry beats the wildcard, whichever comes first.
+        headers.set_raw("Accept-Encoding", "*;q=0, gzip");
+        assert_eq!(accepts_encoding(&headers, "gzip"), Some(1.0));
+        assert_eq!(accepts_encoding(&headers, "br"), None);
+        headers.set_raw("Accept-Encoding", "gzip;q=0, *");
+        assert_eq!(accepts_encoding(&headers, "gzip"), None);
+        assert_eq!(accepts_encoding(&headers, "br"), Some(1.0));
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Brotli));
+
+        // The client's preference beats ours, and ours only breaks ties.
+        headers.set_raw("Accept-Encoding", "gzip;q=1, br;q=0.1");
+        assert_eq!(accepts_encoding(&headers, "br"), Some(0.1));
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Gzip));
+        headers.set_raw("Accept-Encoding", "gzip;q=0.5, br;q=0.5");
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let options = CompressionOptions::default();
+        let html = headers(&[("content-type", "text/html; charset=utf-8")]);
+        assert!(should_compress(&options, 200, &html, 2048, false));
+        assert!(!should_compress(&options, 200, &html, 100, false));
+        assert!(should_compress(&options, 200, &html, 100, true));
+        assert!(!should_compress(&options, 304, &html, 2048, false));
+        assert!(!should_compress(&options, 206, &html, 2048, false));
+
+        let image = headers(&[("content-type", "image/png")]);
+        assert!(!should_compress(&options, 200, &image, 2048, false));
+
+        let encoded = headers(&[("content-type", "text/html"), ("content-encoding", "gzip")]);
+        assert!(!should_compress(&options, 200, &encoded, 2048, false));
+
+        let short = headers(&[("content-type", "text/html"), ("content-length", "100")]);
+        assert!(!should_compress(&options, 200, &short, 10, true));
+
+        let no_transform = headers(&[("content-type", "text/html"),
+                                     ("Cache-Control", "public, No-Transform")]);
+        assert!(!should_compress(&options, 200, &no_transform, 2048, false));
+        let cacheable = headers(&[("content-type", "text/html"), ("cache-control", "max-age=60")]);
+        assert!(should_compress(&options, 200, &cacheable, 2048, false));
+    }
+
+    #[test]
+    fn test_weaken_etag() {
+        assert_eq!(weaken_etag(b"\"abc\"".to_vec()), b"W/\"abc\"".to_vec());
+        assert_eq!(weaken_etag(b"W/\"abc\"".to_vec()), b"W/\"abc\"".to_vec());
+    }
+
+    #[test]
+    fn test_gzip_encoder_streams() {
+        let mut encoder = Encoder::new(Encoding::Gzip);
+        let mut compressed = encoder.compress(b"Hello, ").unwrap();
+        assert!(!compressed.is_empty());
+        compressed.extend(encoder.compress(b"world!").unwrap());
+        compressed.extend(encoder.finish().unwrap());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..]).unwrap().read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "Hello, world!");
+    }
+
+    #[test]
+    fn test_gunzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::Default);
+        encoder.write_all(&[b'a'; 1000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(gunzip(&compressed, 1000), Ok(vec![b'a'; 1000]));
+        assert_eq!(gunzip(&compressed, 999), Err(DecompressError::TooLarge));
+        assert_eq!(gunzip(b"not gzip", 1000), Err(DecompressError::Invalid));
+    }
+}
@@ -1,71 +1,304 @@
-use std;
+use std::ascii::AsciiExt;
 use std::clone::Clone;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures_cpupool::CpuPool;
 use futures;
-use futures::{BoxFuture, Future, Stream};
+use futures::{Future, Stream};
 use hyper;
 use hyper::{Headers, HttpVersion, Method, Uri};
-use hyper::header::ContentType;
+use hyper::header::{Connection, ContentLength, ContentType};
 use hyper::mime::{Attr, Mime, SubLevel, TopLevel, Value};
-use hyper::server::{NewService, Response, Request, Service};
+use hyper::server::{Response, Request, Service};
 use hyper::status::StatusCode;
 use r2d2;
 use serde::bytes::{ByteBuf, Bytes};
+use tokio_core::reactor::{Handle, Timeout};
+use url::percent_encoding::percent_decode;
 
 use body::BodyStream;
-use channels::{ChannelError, ChannelLayer, RedisChannelLayer, RedisChannelLayerManager, ReplyPump};
+use breaker::{CircuitBreaker, CircuitBreakerOptions, Outcome};
+use cidr::Cidr;
+use channels;
+use channels::{ChannelError, ChannelLayer, RedisChannelLayer, RedisChannelLayerManager,
+               ReplyChannelGuard, ReplyPump};
+use compression;
+use compression::{CompressionOptions, DecompressError, Encoder, Encoding};
+use limits::{Counter, Limit, Permit};
 use msgs;
+use proxy_headers;
+use ratelimit::{RateLimit, RateLimiter};
+use request_id;
+use routing;
+use routing::Route;
+use shedding::{LoadSheddingOptions, QueueMonitor};
+use slow_clients::{Activity, RequestGuard, TimedBody};
+use static_files;
+use static_files::StaticMount;
+use validate;
+use vhost::VirtualHost;
 
 
-pub struct AsgiHttpServiceFactory<C>
+/// Describes the connection that a service is handling requests for.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// "https" for connections on which we've terminated TLS, "http" otherwise.
+    pub scheme: &'static str,
+    /// The address the connection was accepted on. None for Unix domain sockets.
+    pub server: Option<SocketAddr>,
+    /// The address of the client. None for Unix domain sockets.
+    pub client: Option<SocketAddr>,
+    /// Tells the server when the connection is busy with a request, so that it knows when to
+    /// apply its idle and header timeouts. None if it doesn't have any.
+    pub activity: Option<Activity>,
+}
+
+
+/// Options controlling how we handle requests, shared by every connection.
+#[derive(Clone, Debug, Default)]
+pub struct HttpOptions {
+    /// Trust the client address and scheme given in X-Forwarded-For, X-Forwarded-Proto and
+    /// Forwarded headers on connections from these addresses.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Trust the same headers on connections to our Unix domain sockets, which have no address to
+    /// check against trusted_proxies.
+    pub trust_unix_socket_proxies: bool,
+    /// The path the application is mounted under, e.g. "/api". Sent as root_path.
+    pub root_path: String,
+    /// Whether to remove root_path from the start of the path we send, for servers in front of
+    /// us that don't remove it themselves.
+    pub strip_root_path: bool,
+    /// Directories to serve files from ourselves, rather than passing requests to the application.
+    pub static_mounts: Vec<StaticMount>,
+    /// Compress responses from the application for clients which accept it. None to disable.
+    pub compression: Option<CompressionOptions>,
+    /// Decompress gzipped request bodies, refusing any which decompress to more than this many
+    /// bytes. None to pass them to the application as they are.
+    pub max_decompressed_body_size: Option<usize>,
+    /// Send requests matching these to their own channels, rather than http.request.
+    pub routes: Vec<Route>,
+    /// Give up waiting for the application to respond after this long. None to wait forever.
+    pub request_timeout: Option<Duration>,
+    /// Refuse request bodies larger than this many bytes. None for no limit.
+    pub max_body_size: Option<usize>,
+    /// Refuse requests when the application's workers have fallen behind. None to always queue
+    /// requests for them.
+    pub load_shedding: Option<LoadSheddingOptions>,
+    /// Refuse requests for a while when too many recent requests have timed out. Only useful
+    /// with a request_timeout. None to keep sending requests regardless.
+    pub circuit_breaker: Option<CircuitBreakerOptions>,
+    /// Reply 429 to clients making requests faster than these allow.
+    pub rate_limits: Vec<RateLimit>,
+    /// Keep rate limits' buckets in the channel layer of the application each request is for, so
+    /// that they hold across several instances of the server. Otherwise they're kept in memory.
+    pub shared_rate_limits: bool,
+    /// Reply 503 to requests while this many are waiting on the application, each of which holds
+    /// a reply channel open in the ReplyPump.
+    pub in_flight_limit: Limit,
+    /// Reply 408 if a request's body stops arriving for this long. None to wait forever.
+    pub body_idle_timeout: Option<Duration>,
+    /// Reply 408 if a request's body arrives more slowly than this many bytes per second, after
+    /// a few seconds' grace. None for no minimum.
+    pub min_upload_rate: Option<usize>,
+    /// Reply 431 to requests with more header lines than this. None for no limit.
+    pub max_header_count: Option<usize>,
+}
+
+
+// The channel layers serving one application: a pool to send requests on, and a ReplyPump to
+// receive their responses.
+struct Backend<C>
     where C: ChannelLayer
 {
-    addr: SocketAddr,
     reply_pump: ReplyPump<C>,
     channel_pool: r2d2::Pool<C::Manager>,
+    // Only present when load shedding is enabled.
+    queue_monitor: Option<QueueMonitor>,
+    breaker: Option<Arc<CircuitBreaker>>,
 }
 
-impl<C> AsgiHttpServiceFactory<C>
+impl<C> Clone for Backend<C>
     where C: ChannelLayer
 {
-    pub fn new(addr: &SocketAddr) -> AsgiHttpServiceFactory<RedisChannelLayer> {
-        let connection_info = "redis://127.0.0.1";
+    fn clone(&self) -> Self {
+        Backend {
+            reply_pump: self.reply_pump.clone(),
+            channel_pool: self.channel_pool.clone(),
+            queue_monitor: self.queue_monitor.clone(),
+            breaker: self.breaker.clone(),
+        }
+    }
+}
 
-        // Make a ReplyPump with its own dedicated channel layer.
-        let channel_layer = RedisChannelLayer::new(connection_info.clone()).unwrap();
-        let reply_pump = ReplyPump::new(channel_layer);
 
-        // Make a pool of channel layers that we can use to send requests on.
-        let config = r2d2::Config::builder()
-            .pool_size(15)
-            .build();
-        let manager = RedisChannelLayerManager::new(connection_info).unwrap();
-        let pool = r2d2::Pool::new(config, manager).unwrap();
+// The applications we serve, by the Host they're served for.
+struct Backends<C>
+    where C: ChannelLayer
+{
+    default: Option<Backend<C>>,
+    virtual_hosts: HashMap<String, Backend<C>>,
+}
 
-        AsgiHttpServiceFactory {
-            addr: addr.clone(),
-            reply_pump: reply_pump,
-            channel_pool: pool,
+impl<C> Backends<C>
+    where C: ChannelLayer
+{
+    // Requests for unknown hosts get the default application if there is one. Otherwise we
+    // reply 421 if the client named a host we don't serve, or 404 if it didn't name one at all.
+    fn select(&self, host: Option<&str>) -> Result<&Backend<C>, ErrorResponse> {
+        let backend = host.map(routing::normalize_host)
+            .and_then(|host| self.virtual_hosts.get(&host))
+            .or(self.default.as_ref());
+        match (backend, host) {
+            (Some(backend), _) => Ok(backend),
+            (None, Some(_)) => Err((StatusCode::from_u16(421), "Unknown host")),
+            (None, None) => Err((StatusCode::NotFound, "Unknown host")),
         }
     }
 }
 
-impl<C> NewService for AsgiHttpServiceFactory<C>
+
+pub struct AsgiHttpServiceFactory<C>
     where C: ChannelLayer
 {
-    type Request = Request;
-    type Response = Response<BodyStream<C>>;
-    type Error = hyper::Error;
-    type Instance = AsgiHttpService<C>;
-
-    fn new_service(&self) -> Result<Self::Instance, std::io::Error> {
-        Ok(AsgiHttpService {
-               addr: self.addr.clone(),
-               reply_pump: self.reply_pump.clone(),
-               channel_pool: self.channel_pool.clone(),
-           })
+    options: Arc<HttpOptions>,
+    backends: Arc<Backends<C>>,
+    rate_limiter: Arc<RateLimiter>,
+    in_flight: Counter,
+}
+
+impl AsgiHttpServiceFactory<RedisChannelLayer> {
+    /// Creates a factory talking to the Redis channel layer on localhost, whose ReplyPump has the
+    /// given number of workers.
+    pub fn new(reply_pumps: usize, options: HttpOptions) -> Self {
+        let (reply_pump, pool) = redis_channel_layers(channels::redis::DEFAULT_URL,
+                                                      channels::redis::DEFAULT_PREFIX,
+                                                      reply_pumps);
+        AsgiHttpServiceFactory::with_channel_layers(reply_pump, pool, options)
+    }
+
+    /// Creates a factory serving each of the virtual hosts from its own Redis channel layer.
+    /// Requests for any other host are refused.
+    pub fn with_redis_virtual_hosts(reply_pumps: usize,
+                                    options: HttpOptions,
+                                    vhosts: &[VirtualHost])
+                                    -> Self {
+        let mut factory = AsgiHttpServiceFactory::without_default(options);
+        for vhost in vhosts {
+            let (reply_pump, pool) =
+                redis_channel_layers(&vhost.redis_url, &vhost.prefix, reply_pumps);
+            factory = factory.with_virtual_host(&vhost.host, reply_pump, pool);
+        }
+        factory
+    }
+}
+
+fn redis_channel_layers(connection_info: &str,
+                        prefix: &str,
+                        reply_pumps: usize)
+                        -> (ReplyPump<RedisChannelLayer>, r2d2::Pool<RedisChannelLayerManager>) {
+    // Make a ReplyPump with a dedicated channel layer for each of its workers.
+    let channel_layers = (0..reply_pumps)
+        .map(|_| RedisChannelLayer::with_prefix(connection_info.clone(), prefix).unwrap())
+        .collect();
+    let reply_pump = ReplyPump::new(channel_layers);
+
+    // Make a pool of channel layers that we can use to send requests on.
+    let config = r2d2::Config::builder()
+        .pool_size(15)
+        .build();
+    let manager = RedisChannelLayerManager::with_prefix(connection_info, prefix).unwrap();
+    let pool = r2d2::Pool::new(config, manager).unwrap();
+
+    (reply_pump, pool)
+}
+
+impl<C> AsgiHttpServiceFactory<C>
+    where C: ChannelLayer
+{
+    /// Creates a factory for any channel layer. Requests are sent using layers from the pool, and
+    /// responses are received by the ReplyPump.
+    pub fn with_channel_layers(reply_pump: ReplyPump<C>,
+                               channel_pool: r2d2::Pool<C::Manager>,
+                               options: HttpOptions)
+                               -> Self {
+        let mut factory = AsgiHttpServiceFactory::without_default(options);
+        let backend = factory.backend(reply_pump, channel_pool);
+        Arc::get_mut(&mut factory.backends).unwrap().default = Some(backend);
+        factory
+    }
+
+    /// Creates a factory which only serves the virtual hosts added to it.
+    pub fn without_default(options: HttpOptions) -> Self {
+        let rate_limits = options.rate_limits.clone();
+        let rate_limiter = match options.shared_rate_limits {
+            true => RateLimiter::shared(rate_limits),
+            false => RateLimiter::new(rate_limits),
+        };
+        AsgiHttpServiceFactory {
+            in_flight: Counter::new(options.in_flight_limit),
+            options: Arc::new(options),
+            rate_limiter: Arc::new(rate_limiter),
+            backends: Arc::new(Backends {
+                default: None,
+                virtual_hosts: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Serves requests for the host using these channel layers, rather than the default ones.
+    /// Virtual hosts must all be added before any services are created.
+    pub fn with_virtual_host(mut self,
+                             host: &str,
+                             reply_pump: ReplyPump<C>,
+                             channel_pool: r2d2::Pool<C::Manager>)
+                             -> Self {
+        let backend = self.backend(reply_pump, channel_pool);
+        Arc::get_mut(&mut self.backends)
+            .expect("Virtual host added after creating services")
+            .virtual_hosts
+            .insert(routing::normalize_host(host), backend);
+        self
+    }
+
+    fn backend(&self,
+               reply_pump: ReplyPump<C>,
+               channel_pool: r2d2::Pool<C::Manager>)
+               -> Backend<C> {
+        // Watch every channel we might send requests on.
+        let queue_monitor = self.options.load_shedding.as_ref().map(|_| {
+            let mut channels = vec![routing::DEFAULT_CHANNEL.to_owned()];
+            for route in &self.options.routes {
+                if !channels.contains(&route.channel) {
+                    channels.push(route.channel.clone());
+                }
+            }
+            QueueMonitor::start::<C>(channel_pool.clone(), channels)
+        });
+        Backend {
+            reply_pump: reply_pump,
+            channel_pool: channel_pool,
+            queue_monitor: queue_monitor,
+            breaker: self.options
+                .circuit_breaker
+                .as_ref()
+                .map(|options| Arc::new(CircuitBreaker::new(options.clone()))),
+        }
+    }
+
+    /// Creates a service to handle the requests arriving on a single connection, which is being
+    /// served by the given event loop.
+    pub fn new_service(&self, handle: &Handle, connection: ConnectionInfo) -> AsgiHttpService<C> {
+        AsgiHttpService {
+            options: self.options.clone(),
+            handle: handle.clone(),
+            connection: connection,
+            backends: self.backends.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            in_flight: self.in_flight.clone(),
+        }
     }
 }
 
@@ -73,9 +306,12 @@ impl<C> NewService for AsgiHttpServiceFactory<C>
 pub struct AsgiHttpService<C>
     where C: ChannelLayer
 {
-    addr: SocketAddr,
-    reply_pump: ReplyPump<C>,
-    channel_pool: r2d2::Pool<C::Manager>,
+    options: Arc<HttpOptions>,
+    handle: Handle,
+    connection: ConnectionInfo,
+    backends: Arc<Backends<C>>,
+    rate_limiter: Arc<RateLimiter>,
+    in_flight: Counter,
 }
 
 impl<C> Service for AsgiHttpService<C>
@@ -84,65 +320,370 @@ impl<C> Service for AsgiHttpService<C>
     type Request = Request;
     type Response = Response<BodyStream<C>>;
     type Error = hyper::Error;
-    type Future = BoxFuture<Self::Response, Self::Error>;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        let remote_addr = req.remote_addr().map(|a| a.clone());
-        let (method, uri, version, headers, body) = req.deconstruct();
+        let request_id = request_id::for_request(req.headers());
+        let response = self.respond(req, request_id.clone()).map(move |mut response| {
+            response.headers_mut().set_raw(request_id::HEADER, request_id);
+            response
+        });
+        Box::new(response)
+    }
+}
+
+impl<C> AsgiHttpService<C>
+    where C: ChannelLayer
+{
+    fn respond(&self,
+               req: Request,
+               request_id: String)
+               -> Box<Future<Item = Response<BodyStream<C>>, Error = hyper::Error>> {
+        let (method, uri, version, mut headers, body) = req.deconstruct();
+        let mut request = self.connection.activity.as_ref().map(Activity::begin_request);
+
+        let version = match asgi_http_version(&version) {
+            Some(version) => version,
+            None => {
+                let response = error_response(StatusCode::HttpVersionNotSupported,
+                                              "Unsupported HTTP version");
+                return Box::new(futures::future::ok(response));
+            }
+        };
+
+        // Hyper limits how large the headers may be, but not how many of them there are.
+        if let Some(max) = self.options.max_header_count {
+            let count: usize = headers.iter()
+                .map(|header| headers.get_raw(header.name()).map_or(0, |raw| raw.iter().count()))
+                .sum();
+            if count > max {
+                let response = error_response(StatusCode::from_u16(431),
+                                              "Too many request headers");
+                return Box::new(futures::future::ok(response));
+            }
+        }
+
+        // Pass the ID on to the application, replacing anything we didn't accept. We add it after
+        // counting the headers, as it isn't one of the client's.
+        headers.set_raw(request_id::HEADER, request_id.clone());
+
+        // The ASGI path is decoded, but we must be able to decode it as UTF-8 to send it.
+        let path = match decode_path(uri.path()) {
+            Some(path) => path,
+            None => {
+                let response = error_response(StatusCode::BadRequest, "Invalid request path");
+                return Box::new(futures::future::ok(response));
+            }
+        };
+
+        // Whether the client will take trailers, which we need to know before we hand its headers
+        // over.
+        let accepts_trailers = accepts_trailers(&headers);
+
+        // Decide how we'd like to compress the response before we hand the headers over.
+        let encoding = match (self.options.compression.is_some(), &method) {
+            (true, &Method::Head) | (false, _) => None,
+            (true, _) => Encoding::negotiate(&headers),
+        };
+
         let cpu_pool = CpuPool::new(4);
 
-        let reply_pump = self.reply_pump.clone();
-        let channel_pool: r2d2::Pool<C::Manager> = self.channel_pool.clone();
-        let local_addr = self.addr.clone();
+        // Serve static files without bothering the application.
+        if let Some(response) = static_files::serve(&self.options.static_mounts,
+                                                    &method,
+                                                    &path,
+                                                    &headers,
+                                                    &cpu_pool,
+                                                    &mut request) {
+            return response;
+        }
+
+        // Work out which application the request is for, which channel to send it on, and how
+        // patient to be with it.
+        let host = request_host(&uri, &headers);
+        let backend = match self.backends.select(host.as_ref().map(String::as_ref)) {
+            Ok(backend) => backend.clone(),
+            Err((status, message)) => {
+                return Box::new(futures::future::ok(error_response(status, message)));
+            }
+        };
+        let route = routing::select(&self.options.routes, host.as_ref().map(String::as_ref), &path);
+        let channel = route.map_or(routing::DEFAULT_CHANNEL, |route| &route.channel).to_owned();
+        let timeout = route.and_then(|route| route.timeout).or(self.options.request_timeout);
+        let max_body_size =
+            route.and_then(|route| route.max_body_size).or(self.options.max_body_size);
+
+        // Fail fast if the application's workers are already too far behind to get to this
+        // request in good time.
+        if let (&Some(ref shedding), &Some(ref monitor)) =
+            (&self.options.load_shedding, &backend.queue_monitor) {
+            if !shedding.is_exempt(&path) &&
+               monitor.queue_length(&channel) > shedding.max_queue_length {
+                let response = error_response(StatusCode::ServiceUnavailable, "Server overloaded");
+                return Box::new(futures::future::ok(response));
+            }
+        }
+
+        // Don't bother reading bodies that we already know are too large.
+        let declared_len = headers.get::<ContentLength>().map(|&ContentLength(len)| len);
+        if let (Some(max), Some(len)) = (max_body_size, declared_len) {
+            if len > max as u64 {
+                let (status, message) = body_too_large();
+                return Box::new(futures::future::ok(error_response(status, message)));
+            }
+        }
+
+        let connection = proxy_headers::resolve(&self.options.trusted_proxies,
+                                                self.options.trust_unix_socket_proxies,
+                                                &headers,
+                                                &self.connection);
+
+        // Check the client's rate limits on the thread-pool, as their buckets may be in Redis.
+        let client_ip = connection.client.map(|addr| addr.ip());
+        let buckets = self.rate_limiter.buckets(client_ip, &headers, &path);
+        let rate_limiter = self.rate_limiter.clone();
+        let limit_pool = backend.channel_pool.clone();
+        let rate_limited: Box<Future<Item = Result<(), Duration>, Error = ErrorResponse>> =
+            match buckets.is_empty() {
+                true => Box::new(futures::future::ok(Ok(()))),
+                false => {
+                    Box::new(cpu_pool.spawn_fn(move || {
+                        Ok(rate_limiter.take::<C>(&buckets, Some(&limit_pool)))
+                    }))
+                }
+            };
+
+        let in_flight = self.in_flight.clone();
+        let breaker = backend.breaker.clone();
+        let reply_pump = backend.reply_pump.clone();
+        let send_reply_pump = backend.reply_pump;
+        let channel_pool: r2d2::Pool<C::Manager> = backend.channel_pool;
+        let options = self.options.clone();
+        let response_options = self.options.clone();
+        let handle = self.handle.clone();
+        let send_cpu_pool = cpu_pool.clone();
+        let send_request_id = request_id.clone();
+        let wait_request_id = request_id.clone();
+        let response_request_id = request_id.clone();
 
         // We chain a series of futures together in order to handle the request/response async.
-        // We don't actually care about the errors of the individual stages, as we'll return a
-        // generic error response to the client, so we keep it simple and  map all errors to ().
-        body
+        // We don't actually care about the errors of most of the individual stages, as we'll
+        // return a generic error response to the client, so we keep it simple and map them all
+        // to the same ErrorResponse. Nothing happens until the chain is polled, which we only do
+        // once the request has passed the checks below.
+        let body = TimedBody::new(body,
+                                  self.options.body_idle_timeout,
+                                  self.options.min_upload_rate,
+                                  &self.handle);
+        let forward = body
             // Wait for the entire body of the request to be in memory before proceeding. It feels
             // like it would be nice to send each chunk over ASGI separately, but once Channels
             // receives a http.request, it blocks while it waits for its body. Buffer the entire
-            // body here to avoid blocking in the sync back-end Channels worker processes.
-            .collect()
-            .map_err(|_| ())
-            // Convert our Vec<Chunk> to a Vec<u8>.
-            .map(|body| {
-                body.iter()
-                    .fold(Vec::new(), |mut vec, chunk| {
-                        vec.extend_from_slice(&chunk);
-                        vec
-                    })
+            // body here to avoid blocking in the sync back-end Channels worker processes. Clients
+            // which take too long to send it get a 408.
+            .fold(Vec::new(), move |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                match max_body_size {
+                    Some(max) if body.len() > max => Err(body_too_large()),
+                    _ => Ok(body),
+                }
             })
             // Send our body down the channel. To keep the code simple we do this in one
-            // synchronous operation on the thread-pool.
+            // synchronous operation on the thread-pool, along with decompressing it if need be.
             .and_then(move |body| {
-                cpu_pool.spawn_fn(move || {
+                send_cpu_pool.spawn_fn(move || {
+                    let (headers, body) = decompress_request(&options, headers, body)?;
                     send_request_sync::<C>(
-                        channel_pool, method, uri, version, headers, body,
-                        remote_addr, &local_addr)
+                        channel_pool, &send_reply_pump, &options, &channel, method, uri, &path,
+                        version, headers, body, &connection)
+                        .map_err(|err| {
+                            println!("Failed to send request {} on {}: {}",
+                                     send_request_id, channel, err);
+                            internal_error(err)
+                        })
                 })
-                .map_err(|_| ())
             })
-            .map_err(|_| ())
             // We wait for the initial response on the request's reply channel. We'll wait for
             // subsequent chunks inside the body stream.
             .and_then(move |reply_channel| {
-                reply_pump.wait_for_reply_async::<msgs::http::Response>(reply_channel.clone())
-                    .map(move |asgi_response| (reply_pump, reply_channel, asgi_response))
-                    .map_err(|_| ())
+                // Don't leave the pump listening for a reply nobody will collect, e.g. if we time
+                // out or the client goes away. The guard is dropped along with the future.
+                let guard = ReplyChannelGuard::new(reply_pump.clone(), reply_channel.clone());
+                let reply = reply_pump
+                    .wait_for_reply_async::<msgs::http::Response>(reply_channel, wait_request_id)
+                    .map(move |asgi_response| (guard, asgi_response))
+                    .map_err(internal_error);
+                with_timeout(reply, timeout, &handle)
+            });
+
+        let response = rate_limited
+            .and_then(move |rate_limited| -> PendingResponse<C> {
+                if let Err(retry_after) = rate_limited {
+                    let mut response = error_response(StatusCode::TooManyRequests,
+                                                      "Too many requests");
+                    set_retry_after(&mut response, retry_after);
+                    return Box::new(futures::future::ok(response));
+                }
+                // Hold a slot until the application has responded.
+                let permit = match in_flight.try_acquire(client_ip) {
+                    Some(permit) => permit,
+                    None => {
+                        let response = error_response(StatusCode::ServiceUnavailable,
+                                                      "Too many requests in progress");
+                        return Box::new(futures::future::ok(response));
+                    }
+                };
+                // Don't send anything if the application has stopped responding. Once we let a
+                // request through, we must tell the breaker how it went.
+                let ticket = match breaker {
+                    Some(breaker) => {
+                        match breaker.check() {
+                            Ok(ticket) => Some((breaker, ticket)),
+                            Err(retry_after) => {
+                                let response = unavailable_response(breaker.options(),
+                                                                    retry_after);
+                                return Box::new(futures::future::ok(response));
+                            }
+                        }
+                    }
+                    None => None,
+                };
+                let forward = forward.then(move |result| {
+                    if let Some((breaker, ticket)) = ticket {
+                        breaker.record(ticket, match result {
+                            Ok(_) => Outcome::Responded,
+                            Err(ref err) if err.0 == StatusCode::GatewayTimeout => {
+                                Outcome::TimedOut
+                            }
+                            Err(_) => Outcome::Abandoned,
+                        });
+                    }
+                    result
+                });
+                // Start sending the response to the client. If this is a streaming response,
+                // we'll return a body stream which continues to send chunks as we receive them,
+                // and holds on to the slot until the last of them.
+                Box::new(forward.and_then(move |reply| {
+                    send_response(&response_options,
+                                  encoding,
+                                  request,
+                                  permit,
+                                  accepts_trailers,
+                                  &response_request_id,
+                                  reply)
+                }))
             })
-            // Start sending the response to the client. If this is a streaming response, we'll
-            // return a body stream which continues to send chunks as we receive them.
-            .and_then(send_response)
-            // If we encountered any error along the way, give a generic error response to the
-            // client. Note that we do not propogate the error to Hyper - the request has succeeded
-            // as far as it's concerned.
-            .or_else(|_| {
-                futures::future::ok(
-                    error_response(StatusCode::InternalServerError, "Unknown server error")
-                )
+            // If we encountered any error along the way, give an error response to the client.
+            // Note that we do not propogate the error to Hyper - the request has succeeded as far
+            // as it's concerned.
+            .or_else(move |(status, body)| {
+                println!("Request {} failed: {} {}", request_id, status, body);
+                let mut response = error_response(status, body);
+                // Whatever's left of a slow request body would be mistaken for the next request.
+                if status == StatusCode::RequestTimeout {
+                    response.headers_mut().set(Connection::close());
+                }
+                futures::future::ok(response)
+            });
+        Box::new(response)
+    }
+}
+
+
+// Most failures to handle a request get a generic error response, but some deserve a more
+// specific one. This is the status and message to give the client.
+type ErrorResponse = (StatusCode, &'static str);
+
+type PendingResponse<C> = Box<Future<Item = Response<BodyStream<C>>, Error = ErrorResponse>>;
+
+fn internal_error<E>(_: E) -> ErrorResponse {
+    (StatusCode::InternalServerError, "Unknown server error")
+}
+
+fn body_too_large() -> ErrorResponse {
+    (StatusCode::PayloadTooLarge, "Request body too large")
+}
+
+
+// Fails the future with a 504 if it doesn't complete within the timeout.
+fn with_timeout<F>(future: F,
+                   timeout: Option<Duration>,
+                   handle: &Handle)
+                   -> Box<Future<Item = F::Item, Error = ErrorResponse>>
+    where F: 'static + Future<Error = ErrorResponse>,
+          F::Item: 'static
+{
+    let timer = match timeout.map(|timeout| Timeout::new(timeout, handle)) {
+        Some(Ok(timer)) => timer,
+        Some(Err(err)) => return Box::new(futures::future::err(internal_error(err))),
+        None => return Box::new(future),
+    };
+    let timed_out = timer.then(|_| {
+        Err::<F::Item, ErrorResponse>((StatusCode::GatewayTimeout, "Application timed out"))
+    });
+    Box::new(future.select(timed_out).map(|(item, _)| item).map_err(|(err, _)| err))
+}
+
+
+/// The http_version to send over ASGI for requests of this HTTP version. Hyper only speaks HTTP/1.x
+/// on the wire, and we don't know how to represent anything else, so reject requests using them.
+fn asgi_http_version(version: &HttpVersion) -> Option<&'static str> {
+    match *version {
+        HttpVersion::Http10 => Some("1.0"),
+        HttpVersion::Http11 => Some("1.1"),
+        _ => None,
+    }
+}
+
+
+/// Whether the client will accept trailers in a chunked response, i.e. it sent `TE: trailers`.
+fn accepts_trailers(headers: &Headers) -> bool {
+    headers.get_raw("TE").map_or(false, |raw| {
+        raw.iter().any(|line| {
+            String::from_utf8_lossy(line).split(',').any(|coding| {
+                coding.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("trailers")
             })
-            .boxed()
+        })
+    })
+}
+
+
+/// The host the request was for, from its absolute URI or Host header.
+fn request_host(uri: &Uri, headers: &Headers) -> Option<String> {
+    match uri.host() {
+        Some(host) => Some(host.to_owned()),
+        None => {
+            headers.get_raw("Host")
+                .and_then(|raw| raw.one())
+                .map(|host| String::from_utf8_lossy(host).into_owned())
+        }
+    }
+}
+
+
+/// Percent-decodes the path of a request. Returns None if the result isn't valid UTF-8.
+fn decode_path(path: &str) -> Option<String> {
+    percent_decode(path.as_bytes())
+        .decode_utf8()
+        .ok()
+        .map(|path| path.into_owned())
+}
+
+
+/// Removes root_path from the start of path, if path is within it. The result always begins with
+/// a slash, so that it's still a valid path.
+fn strip_root_path<'a>(path: &'a str, root_path: &str) -> &'a str {
+    let root_path = root_path.trim_right_matches('/');
+    if root_path.is_empty() || !path.starts_with(root_path) {
+        return path;
+    }
+    let rest = &path[root_path.len()..];
+    match rest {
+        // Use the root path's trailing slash, rather than returning an empty path.
+        "" => "/",
+        _ if rest.starts_with('/') => rest,
+        // The path only shares a prefix with the root path, e.g. /apiary and /api.
+        _ => path,
     }
 }
 
@@ -165,14 +706,52 @@ fn format_headers(headers: Headers) -> Vec<(ByteBuf, ByteBuf)> {
 }
 
 
+/// If we've been asked to, transparently decompress gzipped request bodies so that the
+/// application doesn't have to, fixing up the headers to describe the decompressed body.
+fn decompress_request(options: &HttpOptions,
+                      mut headers: Headers,
+                      body: Vec<u8>)
+                      -> Result<(Headers, Vec<u8>), ErrorResponse> {
+    let limit = match options.max_decompressed_body_size {
+        Some(limit) => limit,
+        None => return Ok((headers, body)),
+    };
+
+    let encodings: Vec<String> = match headers.get_raw("Content-Encoding") {
+        Some(raw) => {
+            raw.iter()
+                .map(|value| String::from_utf8_lossy(value).trim().to_lowercase())
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    if encodings != ["gzip"] && encodings != ["x-gzip"] {
+        return Ok((headers, body));
+    }
+
+    let body = compression::gunzip(&body, limit).map_err(|err| match err {
+            DecompressError::TooLarge => {
+                (StatusCode::PayloadTooLarge, "Decompressed request body too large")
+            }
+            DecompressError::Invalid => (StatusCode::BadRequest, "Invalid gzip request body"),
+        })?;
+    headers.remove_raw("Content-Encoding");
+    headers.set(ContentLength(body.len() as u64));
+    Ok((headers, body))
+}
+
+
 fn send_request_sync<C>(channel_pool: r2d2::Pool<C::Manager>,
+                        reply_pump: &ReplyPump<C>,
+                        options: &HttpOptions,
+                        channel: &str,
                         method: Method,
                         uri: Uri,
-                        version: HttpVersion,
+                        path: &str,
+                        version: &str,
                         headers: Headers,
                         body: Vec<u8>,
-                        remote_addr: Option<SocketAddr>,
-                        local_addr: &SocketAddr)
+                        connection: &ConnectionInfo)
                         -> Result<String, ChannelError>
     where C: ChannelLayer
 {
@@ -191,28 +770,30 @@ fn send_request_sync<C>(channel_pool: r2d2::Pool<C::Manager>,
         false => None,
     };
 
-    let version = match version {
-        HttpVersion::Http10 => "1.0",
-        HttpVersion::Http11 => "1.1",
-        _ => panic!("Unsupported HTTP version"),
-    };
-
     let headers = format_headers(headers);
 
-    let client = remote_addr.map(|addr| (format!("{}", addr.ip()), addr.port()));
-    let server = (format!("{}", local_addr.ip()), local_addr.port());
+    let path = match options.strip_root_path {
+        true => strip_root_path(path, &options.root_path),
+        false => path,
+    };
+
+    let client = connection.client.map(|addr| (format!("{}", addr.ip()), addr.port()));
+    let server = connection.server.map(|addr| (format!("{}", addr.ip()), addr.port()));
 
-    // Send the initial chunk of the request to http.request. We must use an extra scope for this
+    // Send the initial chunk of the request to its channel. We must use an extra scope for this
     // because otherwise we'll find reply_channel is borrowed for longer than necessary.
-    let reply_channel = channels.new_channel("http.response!")?;
+    let reply_channel = reply_pump.new_reply_channel(&channels)?;
     {
-        channels.send("http.request",
+        channels.send(channel,
                       &msgs::http::Request {
                            reply_channel: &reply_channel,
-                           http_version: &version,
+                           http_version: version,
                            method: method.as_ref(),
-                           scheme: uri.scheme().unwrap_or("http"),
-                           path: uri.path(),
+                           scheme: connection.scheme,
+                           path: path,
+                           // Hyper gives us the path exactly as it was in the request line.
+                           raw_path: Bytes::from(uri.path().as_bytes()),
+                           root_path: &options.root_path,
                            query_string: uri.query().unwrap_or(""),
                            headers: headers,
                            body: Bytes::from(initial_chunk),
@@ -245,23 +826,90 @@ fn send_request_sync<C>(channel_pool: r2d2::Pool<C::Manager>,
 }
 
 
-fn send_response<C>((pump, channel, asgi_resp): (ReplyPump<C>, String, msgs::http::Response))
-                    -> Result<Response<BodyStream<C>>, ()>
+fn send_response<C>(options: &HttpOptions,
+                    encoding: Option<Encoding>,
+                    request: Option<RequestGuard>,
+                    in_flight: Permit,
+                    accepts_trailers: bool,
+                    request_id: &str,
+                    (reply_channel, asgi_resp): (ReplyChannelGuard<C>, msgs::http::Response))
+                    -> Result<Response<BodyStream<C>>, ErrorResponse>
     where C: ChannelLayer
 {
+    let headers: Vec<(Vec<u8>, Vec<u8>)> = asgi_resp.headers
+        .into_iter()
+        .map(|(name, value)| (name.into(), value.into()))
+        .collect();
+    let headers = match validate::sanitize_response(asgi_resp.status, headers) {
+        Ok((headers, removed)) => {
+            for name in removed {
+                println!("Ignoring hop-by-hop header set by application for request {}: {}",
+                         request_id,
+                         name);
+            }
+            headers
+        }
+        Err(err) => {
+            println!("Invalid response to request {} on {}: {}",
+                     request_id,
+                     reply_channel.channel(),
+                     err);
+            return Err((StatusCode::BadGateway, "Invalid response from application"));
+        }
+    };
+
+    // Work out whether we'd compress this response for a client which accepted it, and whether
+    // this client does.
+    let compressible = match options.compression {
+        Some(ref compression) => {
+            compression::should_compress(compression,
+                                         asgi_resp.status,
+                                         &headers,
+                                         asgi_resp.content.len(),
+                                         asgi_resp.more_content)
+        }
+        None => false,
+    };
+    let encoding = match compressible {
+        true => encoding,
+        false => None,
+    };
+
     let mut resp: Response<BodyStream<C>> = Response::new();
     resp.set_status(StatusCode::from_u16(asgi_resp.status));
-    for (name, value) in asgi_resp.headers {
-        let name = String::from_utf8(name.into()).unwrap();
-        let value: Vec<u8> = value.into();
-        resp.headers_mut().set_raw(name, value);
+    for (name, value) in headers {
+        // Header names have been validated, so are ASCII.
+        let name = String::from_utf8(name).unwrap();
+        // We don't know how long the body will be once it's compressed.
+        if encoding.is_some() && name.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        let value = match encoding.is_some() && name.eq_ignore_ascii_case("etag") {
+            true => compression::weaken_etag(value),
+            false => value,
+        };
+        // Keep every instance of repeated headers, e.g. Set-Cookie.
+        resp.headers_mut().append_raw(name, value);
+    }
+    if compressible {
+        resp.headers_mut().append_raw("Vary", "Accept-Encoding");
+    }
+    if let Some(encoding) = encoding {
+        resp.headers_mut().set_raw("Content-Encoding", encoding.name());
     }
 
     let initial_chunk = msgs::http::ResponseBodyChunk {
         content: asgi_resp.content,
         more_content: asgi_resp.more_content,
+        trailers: asgi_resp.trailers,
     };
-    let stream = BodyStream::response(pump, channel, initial_chunk);
+    let stream = BodyStream::response(reply_channel,
+                                      initial_chunk,
+                                      encoding.map(Encoder::new),
+                                      request,
+                                      in_flight,
+                                      accepts_trailers,
+                                      request_id.to_owned());
     Ok(resp.with_body(stream))
 }
 
@@ -269,7 +917,35 @@ fn send_response<C>((pump, channel, asgi_resp): (ReplyPump<C>, String, msgs::htt
 fn error_response<C>(status: StatusCode, body: &str) -> Response<BodyStream<C>>
     where C: ChannelLayer
 {
-    let body = format!(include_str!("error.html"), status = status, body = body);
+    html_response(status,
+                  format!(include_str!("error.html"), status = status, body = body))
+}
+
+// What we serve while the circuit breaker is open.
+fn unavailable_response<C>(options: &CircuitBreakerOptions,
+                           retry_after: Duration)
+                           -> Response<BodyStream<C>>
+    where C: ChannelLayer
+{
+    let mut response = match options.maintenance_page {
+        Some(ref page) => html_response(StatusCode::ServiceUnavailable, page.clone()),
+        None => error_response(StatusCode::ServiceUnavailable, "Service temporarily unavailable"),
+    };
+    set_retry_after(&mut response, retry_after);
+    response
+}
+
+fn set_retry_after<C>(response: &mut Response<BodyStream<C>>, retry_after: Duration)
+    where C: ChannelLayer
+{
+    // Round up, so that clients don't come back before we're ready for them.
+    let secs = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+    response.headers_mut().set_raw("Retry-After", secs.to_string());
+}
+
+fn html_response<C>(status: StatusCode, body: String) -> Response<BodyStream<C>>
+    where C: ChannelLayer
+{
     Response::new()
         .with_status(status)
         .with_header(ContentType(Mime(TopLevel::Text,
@@ -281,11 +957,52 @@ fn error_response<C>(status: StatusCode, body: &str) -> Response<BodyStream<C>>
 
 #[cfg(test)]
 mod tests {
-    use super::format_headers;
+    use super::{accepts_trailers, asgi_http_version, decode_path, format_headers,
+                strip_root_path};
 
-    use hyper::Headers;
+    use hyper::{Headers, HttpVersion};
     use serde::bytes::ByteBuf;
 
+    #[test]
+    fn asgi_http_versions() {
+        assert_eq!(asgi_http_version(&HttpVersion::Http10), Some("1.0"));
+        assert_eq!(asgi_http_version(&HttpVersion::Http11), Some("1.1"));
+        assert_eq!(asgi_http_version(&HttpVersion::H2), None);
+        assert_eq!(asgi_http_version(&HttpVersion::Http09), None);
+    }
+
+    #[test]
+    fn decode_paths() {
+        assert_eq!(decode_path("/users/"), Some("/users/".to_owned()));
+        assert_eq!(decode_path("/hello%20world"), Some("/hello world".to_owned()));
+        assert_eq!(decode_path("/caf%C3%A9"), Some("/café".to_owned()));
+        assert_eq!(decode_path("/a%2Fb"), Some("/a/b".to_owned()));
+        assert_eq!(decode_path("/bad%FF"), None);
+    }
+
+    #[test]
+    fn accepts_trailers_te() {
+        let mut headers = Headers::new();
+        assert!(!accepts_trailers(&headers));
+        headers.set_raw("TE", "gzip");
+        assert!(!accepts_trailers(&headers));
+        headers.set_raw("TE", "gzip;q=0.5, Trailers");
+        assert!(accepts_trailers(&headers));
+        headers.set_raw("TE", "trailers");
+        assert!(accepts_trailers(&headers));
+    }
+
+    #[test]
+    fn strip_root_paths() {
+        assert_eq!(strip_root_path("/api/users", "/api"), "/users");
+        assert_eq!(strip_root_path("/api/users", "/api/"), "/users");
+        assert_eq!(strip_root_path("/api", "/api"), "/");
+        assert_eq!(strip_root_path("/api/", "/api"), "/");
+        assert_eq!(strip_root_path("/apiary", "/api"), "/apiary");
+        assert_eq!(strip_root_path("/other", "/api"), "/other");
+        assert_eq!(strip_root_path("/users", ""), "/users");
+    }
+
     #[test]
     fn format_headers_single_values() {
         let mut headers = Headers::new();
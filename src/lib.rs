@@ -0,0 +1,58 @@
+//! An ASGI front-end for Hyper. Requests are sent to the application over a channel layer, and
+//! its responses are streamed back to the client.
+//!
+//! Embed it by building an `AsgiHttpServiceFactory`, either for Redis with `new` or for your own
+//! `ChannelLayer` with `with_channel_layers`. Hand that to a `Server` to have us accept
+//! connections, or create services with `new_service` to drive them alongside your own.
+
+extern crate brotli2;
+extern crate crossbeam;
+extern crate flate2;
+extern crate futures_cpupool;
+extern crate futures;
+extern crate hyper;
+extern crate mime_guess;
+extern crate r2d2;
+extern crate rand;
+extern crate redis;
+extern crate ring;
+extern crate rmp_serde;
+extern crate rmp;
+extern crate rustls;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_rustls;
+extern crate tokio_signal;
+extern crate tokio_uds;
+extern crate url;
+
+pub mod body;
+pub mod breaker;
+pub mod channels;
+pub mod cidr;
+pub mod compression;
+pub mod http;
+pub mod limits;
+pub mod msgs;
+mod proxy_headers;
+mod proxy_protocol;
+pub mod ratelimit;
+mod request_id;
+pub mod routing;
+pub mod server;
+pub mod shedding;
+pub mod slow_clients;
+pub mod static_files;
+pub mod tls;
+#[cfg(test)]
+mod test_utils;
+mod validate;
+pub mod vhost;
+pub mod worker;
+
+pub use channels::{ChannelError, ChannelLayer, RedisChannelLayer, ReplyPump};
+pub use http::{AsgiHttpService, AsgiHttpServiceFactory, ConnectionInfo, HttpOptions};
+pub use server::Server;
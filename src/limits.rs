@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures;
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Timeout};
+
+
+/// A cap on how many of something (connections, requests) may be open at once, both in total and
+/// for any one client address. None for no cap.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limit {
+    pub total: Option<usize>,
+    pub per_client: Option<usize>,
+}
+
+
+#[derive(Default)]
+struct Usage {
+    total: usize,
+    by_client: HashMap<IpAddr, usize>,
+    // Everyone waiting for a slot, in the order they started waiting. Whenever a slot is released
+    // we wake the first who can take it.
+    waiters: VecDeque<Waiter>,
+    next_waiter: u64,
+}
+
+struct Waiter {
+    id: u64,
+    client: Option<IpAddr>,
+    woken: oneshot::Sender<()>,
+}
+
+impl Usage {
+    // Joins the queue, returning our place in it and how we'll be woken.
+    fn join(&mut self, client: Option<IpAddr>, front: bool) -> (u64, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_waiter;
+        self.next_waiter += 1;
+        let waiter = Waiter {
+            id: id,
+            client: client,
+            woken: tx,
+        };
+        match front {
+            true => self.waiters.push_front(waiter),
+            false => self.waiters.push_back(waiter),
+        }
+        (id, rx)
+    }
+
+    // Takes the first waiter who'd be able to take a free slot out of the queue, and wakes them.
+    fn wake_next(&mut self, limit: &Limit) {
+        let next = self.waiters.iter().position(|waiter| has_room(limit, self, waiter.client));
+        if let Some(waiter) = next.and_then(|index| self.waiters.remove(index)) {
+            waiter.woken.complete(());
+        }
+    }
+}
+
+fn has_room(limit: &Limit, usage: &Usage, client: Option<IpAddr>) -> bool {
+    if limit.total.map_or(false, |max| usage.total >= max) {
+        return false;
+    }
+    match (client, limit.per_client) {
+        (Some(client), Some(max)) => {
+            usage.by_client.get(&client).map_or(true, |&count| count < max)
+        }
+        _ => true,
+    }
+}
+
+
+/// Hands out slots under a Limit. Clones share the same slots.
+#[derive(Clone)]
+pub struct Counter {
+    limit: Limit,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Counter {
+    pub fn new(limit: Limit) -> Self {
+        Counter {
+            limit: limit,
+            usage: Arc::new(Mutex::new(Usage::default())),
+        }
+    }
+
+    /// Takes a slot for the client, if there's one free. The slot is released when the permit is
+    /// dropped. Clients we don't have an address for only count towards the total.
+    pub fn try_acquire(&self, client: Option<IpAddr>) -> Option<Permit> {
+        let mut usage = self.usage.lock().unwrap();
+        self.take(&mut usage, client)
+    }
+
+    /// Waits up to `timeout` for a slot to become free, failing if one doesn't. Fails straight
+    /// away if `max_waiting` others are already waiting.
+    pub fn acquire(&self,
+                   client: Option<IpAddr>,
+                   max_waiting: usize,
+                   timeout: Duration,
+                   handle: &Handle)
+                   -> Box<Future<Item = Permit, Error = ()>> {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(permit) = self.take(&mut usage, client) {
+            return Box::new(futures::future::ok(permit));
+        }
+        if usage.waiters.len() >= max_waiting {
+            return Box::new(futures::future::err(()));
+        }
+        let timer = match Timeout::new(timeout, handle) {
+            Ok(timer) => timer,
+            Err(_) => return Box::new(futures::future::err(())),
+        };
+        let wait = WaitForSlot {
+            counter: self.clone(),
+            client: client,
+            waiting: Some(usage.join(client, false)),
+        };
+        Box::new(wait.select(timer.then(|_| Err(()))).map(|(permit, _)| permit).map_err(|_| ()))
+    }
+
+    fn take(&self, usage: &mut Usage, client: Option<IpAddr>) -> Option<Permit> {
+        if !has_room(&self.limit, usage, client) {
+            return None;
+        }
+        // We only need to count by client if there's a limit per client.
+        let client = client.and_then(|client| self.limit.per_client.map(|_| client));
+        if let Some(client) = client {
+            *usage.by_client.entry(client).or_insert(0) += 1;
+        }
+        usage.total += 1;
+        Some(Permit {
+            counter: self.clone(),
+            client: client,
+        })
+    }
+}
+
+
+/// A slot taken from a Counter, which is released when this is dropped.
+pub struct Permit {
+    counter: Counter,
+    client: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut usage = self.counter.usage.lock().unwrap();
+        usage.total -= 1;
+        if let Some(client) = self.client {
+            let remaining = {
+                let count = usage.by_client.get_mut(&client).unwrap();
+                *count -= 1;
+                *count
+            };
+            if remaining == 0 {
+                usage.by_client.remove(&client);
+            }
+        }
+        usage.wake_next(&self.counter.limit);
+    }
+}
+
+
+// Resolves to a permit once the counter has a slot free for us.
+struct WaitForSlot {
+    counter: Counter,
+    client: Option<IpAddr>,
+    // Our place in the queue, and how we'll be woken from it.
+    waiting: Option<(u64, oneshot::Receiver<()>)>,
+}
+
+impl Future for WaitForSlot {
+    type Item = Permit;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Permit, ()> {
+        loop {
+            if let Some((_, ref mut woken)) = self.waiting {
+                // A cancelled sender means the counter's gone, which it can't be while we hold
+                // on to it, so treat it like being woken.
+                if let Ok(Async::NotReady) = woken.poll() {
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            // We've been taken out of the queue to try for the slot. If somebody beat us to it,
+            // we've still had our turn, so go back to the front.
+            self.waiting = None;
+            let mut usage = self.counter.usage.lock().unwrap();
+            if let Some(permit) = self.counter.take(&mut usage, self.client) {
+                return Ok(Async::Ready(permit));
+            }
+            self.waiting = Some(usage.join(self.client, true));
+        }
+    }
+}
+
+impl Drop for WaitForSlot {
+    fn drop(&mut self) {
+        if let Some((id, _)) = self.waiting.take() {
+            let mut usage = self.counter.usage.lock().unwrap();
+            match usage.waiters.iter().position(|waiter| waiter.id == id) {
+                Some(index) => {
+                    usage.waiters.remove(index);
+                }
+                // We were woken for a slot we'll now never take, so pass it on.
+                None => usage.wake_next(&self.counter.limit),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, Limit};
+
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use futures::Future;
+    use tokio_core::reactor::{Core, Timeout};
+
+    #[test]
+    fn test_try_acquire() {
+        let counter = Counter::new(Limit {
+            total: Some(3),
+            per_client: Some(2),
+        });
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let bob: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = counter.try_acquire(Some(alice)).unwrap();
+        let _second = counter.try_acquire(Some(alice)).unwrap();
+        assert!(counter.try_acquire(Some(alice)).is_none());
+        let _third = counter.try_acquire(Some(bob)).unwrap();
+        assert!(counter.try_acquire(Some(bob)).is_none());
+        assert!(counter.try_acquire(None).is_none());
+
+        drop(first);
+        assert!(counter.try_acquire(Some(alice)).is_some());
+        assert!(counter.try_acquire(None).is_some());
+    }
+
+    #[test]
+    fn test_acquire() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let counter = Counter::new(Limit {
+            total: Some(1),
+            per_client: None,
+        });
+
+        let permit = counter.try_acquire(None).unwrap();
+        let timeout = Duration::from_millis(10);
+        assert!(core.run(counter.acquire(None, 10, timeout, &handle)).is_err());
+        // Waiters which gave up have left the queue.
+        assert_eq!(counter.usage.lock().unwrap().waiters.len(), 0);
+
+        // Nobody gets to wait once the queue is full.
+        let first = counter.acquire(None, 1, Duration::from_secs(5), &handle);
+        assert!(core.run(counter.acquire(None, 1, Duration::from_secs(5), &handle)).is_err());
+
+        // We get the slot as soon as it's released, in the order we started waiting.
+        let release = Timeout::new(Duration::from_millis(10), &handle)
+            .unwrap()
+            .map(move |_| drop(permit))
+            .map_err(|_| ());
+        handle.spawn(release);
+        let second = counter.acquire(None, 10, Duration::from_secs(5), &handle);
+        let first = core.run(first).unwrap();
+        assert_eq!(counter.usage.lock().unwrap().waiters.len(), 1);
+        drop(first);
+        assert!(core.run(second).is_ok());
+    }
+}
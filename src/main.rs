@@ -1,31 +1,530 @@
-extern crate crossbeam;
-extern crate futures_cpupool;
-extern crate futures;
-extern crate hyper;
-extern crate r2d2;
-extern crate rand;
-extern crate redis;
-extern crate rmp_serde;
-extern crate rmp;
 #[macro_use]
-extern crate serde_derive;
-extern crate serde;
+extern crate clap;
+extern crate asgi_server;
 
-mod body;
-mod channels;
-mod http;
-mod msgs;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use hyper::server::Http;
-
-use channels::RedisChannelLayer;
-use http::AsgiHttpServiceFactory;
+use asgi_server::{AsgiHttpServiceFactory, HttpOptions, RedisChannelLayer, Server};
+use asgi_server::breaker::CircuitBreakerOptions;
+use asgi_server::cidr::Cidr;
+use asgi_server::compression::CompressionOptions;
+use asgi_server::limits::Limit;
+use asgi_server::ratelimit::RateLimit;
+use asgi_server::routing::Route;
+use asgi_server::server::{Bind, Listen};
+use asgi_server::shedding::LoadSheddingOptions;
+use asgi_server::slow_clients::ConnectionTimeouts;
+use asgi_server::static_files::StaticMount;
+use asgi_server::tls::{CertificatePaths, Tls};
+use asgi_server::vhost::VirtualHost;
+use clap::{App, Arg};
 
 
 fn main() {
-    println!("Hello, world!");
-    let addr = "127.0.0.1:8000".parse().unwrap();
-    let server =
-        Http::new().bind(&addr, AsgiHttpServiceFactory::<RedisChannelLayer>::new(&addr)).unwrap();
+    let matches = App::new("asgi-server")
+        .arg(Arg::with_name("bind")
+            .long("bind")
+            .short("b")
+            .value_name("ADDR:PORT")
+            .help("TCP address to listen on [default: 127.0.0.1:8000]")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("tls-bind")
+            .long("tls-bind")
+            .value_name("ADDR:PORT")
+            .help("TCP address to listen on for HTTPS connections")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("tls-cert"))
+        .arg(Arg::with_name("unix-socket")
+            .long("unix-socket")
+            .short("u")
+            .value_name("PATH")
+            .help("Unix domain socket to listen on")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("unix-socket-mode")
+            .long("unix-socket-mode")
+            .value_name("MODE")
+            .help("Octal permissions to give the Unix domain sockets, e.g. 660")
+            .takes_value(true)
+            .requires("unix-socket"))
+        .arg(Arg::with_name("fd")
+            .long("fd")
+            .value_name("FD")
+            .help("Inherited file descriptor of a listening TCP or Unix domain socket, e.g. from \
+                   systemd")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("proxy-protocol")
+            .long("proxy-protocol")
+            .value_name("CIDR")
+            .help("Expect a PROXY protocol v1/v2 header on TCP connections from these addresses")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("proxy-headers")
+            .long("proxy-headers")
+            .value_name("CIDR")
+            .help("Trust X-Forwarded-For, X-Forwarded-Proto and Forwarded headers from these \
+                   addresses")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("proxy-headers-unix")
+            .long("proxy-headers-unix")
+            .help("Trust X-Forwarded-For, X-Forwarded-Proto and Forwarded headers from clients of \
+                   Unix domain sockets"))
+        .arg(Arg::with_name("root-path")
+            .long("root-path")
+            .value_name("PATH")
+            .help("The path the application is mounted under, sent to it as root_path")
+            .takes_value(true))
+        .arg(Arg::with_name("strip-root-path")
+            .long("strip-root-path")
+            .help("Remove --root-path from the start of request paths")
+            .requires("root-path"))
+        .arg(Arg::with_name("static")
+            .long("static")
+            .value_name("PREFIX=DIR")
+            .help("Serve files under DIR for requests with paths beginning with PREFIX")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("compress")
+            .long("compress")
+            .help("Compress responses with gzip or brotli for clients which accept it"))
+        .arg(Arg::with_name("compress-min-size")
+            .long("compress-min-size")
+            .value_name("BYTES")
+            .help("Don't compress responses smaller than this [default: 1024]")
+            .takes_value(true)
+            .requires("compress"))
+        .arg(Arg::with_name("compress-type")
+            .long("compress-type")
+            .value_name("TYPE")
+            .help("Compress responses whose Content-Type begins with this [default: text/, \
+                   application/javascript, application/json, application/xml, image/svg+xml]")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("compress"))
+        .arg(Arg::with_name("decompress-requests")
+            .long("decompress-requests")
+            .value_name("MAX_BYTES")
+            .help("Decompress gzipped request bodies, refusing any larger than this once \
+                   decompressed")
+            .takes_value(true))
+        .arg(Arg::with_name("route")
+            .long("route")
+            .value_name("[HOST]/PREFIX=CHANNEL[,OPTION=VALUE]")
+            .help("Send requests for a host and path prefix to their own channel, e.g. \
+                   api.example.com/v2=http.request.api,timeout=30. Options are timeout (seconds) \
+                   and max-body (bytes).")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("request-timeout")
+            .long("request-timeout")
+            .value_name("SECONDS")
+            .help("Reply 504 if the application hasn't responded within this long")
+            .takes_value(true))
+        .arg(Arg::with_name("max-body-size")
+            .long("max-body-size")
+            .value_name("BYTES")
+            .help("Reply 413 to requests with larger bodies than this")
+            .takes_value(true))
+        .arg(Arg::with_name("max-queue-length")
+            .long("max-queue-length")
+            .value_name("N")
+            .help("Reply 503 to requests while more than this many requests are waiting for a \
+                   worker")
+            .takes_value(true))
+        .arg(Arg::with_name("shed-exempt")
+            .long("shed-exempt")
+            .value_name("PATH_PREFIX")
+            .help("Never reply 503 to requests under this path because of --max-queue-length, \
+                   e.g. for health checks")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("max-queue-length"))
+        .arg(Arg::with_name("breaker-threshold")
+            .long("breaker-threshold")
+            .value_name("RATIO")
+            .help("Refuse requests for a while once this fraction (0-1) of recent requests have \
+                   timed out")
+            .takes_value(true)
+            .validator(validate_ratio)
+            .requires("request-timeout"))
+        .arg(Arg::with_name("breaker-window")
+            .long("breaker-window")
+            .value_name("N")
+            .help("Number of recent requests the circuit breaker considers")
+            .default_value("20")
+            .takes_value(true)
+            .validator(validate_at_least_one))
+        .arg(Arg::with_name("breaker-cooldown")
+            .long("breaker-cooldown")
+            .value_name("SECONDS")
+            .help("How long the circuit breaker refuses requests for before trying again")
+            .default_value("30")
+            .takes_value(true))
+        .arg(Arg::with_name("maintenance-page")
+            .long("maintenance-page")
+            .value_name("FILE")
+            .help("HTML page to serve while the circuit breaker is refusing requests")
+            .takes_value(true)
+            .requires("breaker-threshold"))
+        .arg(Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .value_name("KEY:RATE/UNIT[,burst=N]")
+            .help("Reply 429 to clients making requests faster than this, e.g. ip:10/s,burst=20 \
+                   per client address, header=X-Api-Key:100/m per API key, or path=/api:50/s \
+                   for every request under /api")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("rate-limit-redis")
+            .long("rate-limit-redis")
+            .help("Keep rate limits in the channel layer's Redis (each --vhost's own), so that \
+                   they hold across several instances of the server")
+            .requires("rate-limit"))
+        .arg(Arg::with_name("max-connections")
+            .long("max-connections")
+            .value_name("N")
+            .help("Serve at most this many connections at once")
+            .takes_value(true))
+        .arg(Arg::with_name("max-connections-per-ip")
+            .long("max-connections-per-ip")
+            .value_name("N")
+            .help("Serve at most this many connections from any one client address at once")
+            .takes_value(true))
+        .arg(Arg::with_name("connection-queue-length")
+            .long("connection-queue-length")
+            .value_name("N")
+            .help("How many connections beyond --max-connections or --max-connections-per-ip may \
+                   wait for a slot at once. Any more are closed straight away.")
+            .default_value("128")
+            .takes_value(true))
+        .arg(Arg::with_name("connection-queue-timeout")
+            .long("connection-queue-timeout")
+            .value_name("SECONDS")
+            .help("How long connections beyond --max-connections or --max-connections-per-ip \
+                   wait for a slot before being closed")
+            .default_value("5")
+            .takes_value(true))
+        .arg(Arg::with_name("max-in-flight")
+            .long("max-in-flight")
+            .value_name("N")
+            .help("Reply 503 to requests while this many are waiting on the application")
+            .takes_value(true))
+        .arg(Arg::with_name("max-in-flight-per-ip")
+            .long("max-in-flight-per-ip")
+            .value_name("N")
+            .help("Reply 503 to requests while this many from the same client address are \
+                   waiting on the application")
+            .takes_value(true))
+        .arg(Arg::with_name("header-timeout")
+            .long("header-timeout")
+            .value_name("SECONDS")
+            .help("Reply 408 and close the connection if a request's headers take longer than \
+                   this to arrive, counting from when the connection was accepted or went idle. \
+                   TLS handshakes and PROXY protocol headers get as long, the latter 10 seconds \
+                   if this isn't set.")
+            .takes_value(true))
+        .arg(Arg::with_name("keep-alive-timeout")
+            .long("keep-alive-timeout")
+            .value_name("SECONDS")
+            .help("Close connections which have been idle between requests for this long")
+            .takes_value(true))
+        .arg(Arg::with_name("max-header-size")
+            .long("max-header-size")
+            .value_name("BYTES")
+            .help("Reply 431 and close the connection if a request's headers are larger than this")
+            .takes_value(true))
+        .arg(Arg::with_name("max-header-count")
+            .long("max-header-count")
+            .value_name("N")
+            .help("Reply 431 to requests with more header lines than this")
+            .takes_value(true))
+        .arg(Arg::with_name("body-idle-timeout")
+            .long("body-idle-timeout")
+            .value_name("SECONDS")
+            .help("Reply 408 if a request's body stops arriving for this long")
+            .takes_value(true))
+        .arg(Arg::with_name("min-upload-rate")
+            .long("min-upload-rate")
+            .value_name("BYTES_PER_SECOND")
+            .help("Reply 408 if a request's body arrives more slowly than this")
+            .takes_value(true))
+        .arg(Arg::with_name("vhost")
+            .long("vhost")
+            .value_name("HOST=REDIS_URL[,prefix=PREFIX]")
+            .help("Serve requests for HOST from the application on this Redis channel layer, e.g. \
+                   blog.example.com=redis://127.0.0.1/1,prefix=blog:. Requests for any other \
+                   host are refused.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("reply-pumps")
+            .long("reply-pumps")
+            .value_name("N")
+            .help("Number of threads waiting on reply channels")
+            .default_value("1")
+            .takes_value(true)
+            .validator(validate_at_least_one))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .value_name("[SERVER_NAME=]CERT")
+            .help("PEM certificate chain to serve HTTPS with, optionally only for an SNI name. \
+                   If no --tls-bind is given, every listener serves HTTPS.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("tls-key"))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .value_name("KEY")
+            .help("PEM private key for the corresponding --tls-cert. Only RSA keys are \
+                   supported, in either PKCS#8 or traditional RSA format.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("tls-cert"))
+        .get_matches();
+    let reply_pumps = value_t_or_exit!(matches, "reply-pumps", usize);
+
+    let tls = match (matches.values_of("tls-cert"), matches.values_of("tls-key")) {
+        (Some(certs), Some(keys)) => {
+            let certs: Vec<&str> = certs.collect();
+            let keys: Vec<&str> = keys.collect();
+            if certs.len() != keys.len() {
+                println!("Each --tls-cert must have a corresponding --tls-key");
+                std::process::exit(1);
+            }
+            let paths = certs.iter()
+                .zip(keys.iter())
+                .map(|(cert, key)| CertificatePaths::parse(cert, key))
+                .collect();
+            match Tls::new(paths) {
+                Ok(tls) => Some(tls),
+                Err(err) => {
+                    println!("Failed to load TLS certificates: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // If we've been given certificates but not told which listeners to use them on, then assume
+    // we're only serving HTTPS.
+    let tls_everywhere = tls.is_some() && !matches.is_present("tls-bind");
+
+    let mut listens = Vec::new();
+    if let Some(addrs) = matches.values_of("bind") {
+        for addr in addrs {
+            listens.push(Listen {
+                bind: Bind::Tcp(parse_addr("--bind", addr)),
+                tls: tls_everywhere,
+            });
+        }
+    }
+    if let Some(addrs) = matches.values_of("tls-bind") {
+        for addr in addrs {
+            listens.push(Listen {
+                bind: Bind::Tcp(parse_addr("--tls-bind", addr)),
+                tls: true,
+            });
+        }
+    }
+    if let Some(paths) = matches.values_of("unix-socket") {
+        let mode = matches.value_of("unix-socket-mode").map(|mode| {
+            u32::from_str_radix(mode, 8).unwrap_or_else(|_| {
+                println!("Invalid --unix-socket-mode: {}", mode);
+                std::process::exit(1);
+            })
+        });
+        for path in paths {
+            listens.push(Listen {
+                bind: Bind::Unix(PathBuf::from(path), mode),
+                tls: tls_everywhere,
+            });
+        }
+    }
+    if matches.is_present("fd") {
+        for fd in values_t_or_exit!(matches, "fd", i32) {
+            listens.push(Listen {
+                bind: Bind::Fd(fd),
+                tls: tls_everywhere,
+            });
+        }
+    }
+    if listens.is_empty() {
+        listens.push(Listen {
+            bind: Bind::Tcp(parse_addr("--bind", "127.0.0.1:8000")),
+            tls: tls_everywhere,
+        });
+    }
+
+    let proxy_protocol = if matches.is_present("proxy-protocol") {
+        values_t_or_exit!(matches, "proxy-protocol", Cidr)
+    } else {
+        Vec::new()
+    };
+
+    let mut options = HttpOptions::default();
+    if matches.is_present("proxy-headers") {
+        options.trusted_proxies = values_t_or_exit!(matches, "proxy-headers", Cidr);
+    }
+    options.trust_unix_socket_proxies = matches.is_present("proxy-headers-unix");
+    if let Some(root_path) = matches.value_of("root-path") {
+        options.root_path = root_path.trim_right_matches('/').to_owned();
+    }
+    options.strip_root_path = matches.is_present("strip-root-path");
+    if matches.is_present("compress") {
+        let mut compression = CompressionOptions::default();
+        if matches.is_present("compress-min-size") {
+            compression.min_size = value_t_or_exit!(matches, "compress-min-size", usize);
+        }
+        if let Some(content_types) = matches.values_of("compress-type") {
+            compression.content_types = content_types.map(str::to_lowercase).collect();
+        }
+        options.compression = Some(compression);
+    }
+    if matches.is_present("decompress-requests") {
+        options.max_decompressed_body_size =
+            Some(value_t_or_exit!(matches, "decompress-requests", usize));
+    }
+    if matches.is_present("route") {
+        options.routes = values_t_or_exit!(matches, "route", Route);
+    }
+    if matches.is_present("request-timeout") {
+        let secs = value_t_or_exit!(matches, "request-timeout", u64);
+        options.request_timeout = Some(Duration::from_secs(secs));
+    }
+    if matches.is_present("max-body-size") {
+        options.max_body_size = Some(value_t_or_exit!(matches, "max-body-size", usize));
+    }
+    if matches.is_present("max-queue-length") {
+        options.load_shedding = Some(LoadSheddingOptions {
+            max_queue_length: value_t_or_exit!(matches, "max-queue-length", usize),
+            exempt_paths: matches.values_of("shed-exempt")
+                .map(|paths| paths.map(str::to_owned).collect())
+                .unwrap_or_default(),
+        });
+    }
+    if matches.is_present("breaker-threshold") {
+        let maintenance_page = matches.value_of("maintenance-page").map(|path| {
+            let mut page = String::new();
+            File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut page))
+                .unwrap_or_else(|err| {
+                    println!("Failed to read --maintenance-page {}: {}", path, err);
+                    std::process::exit(1);
+                });
+            page
+        });
+        options.circuit_breaker = Some(CircuitBreakerOptions {
+            window: value_t_or_exit!(matches, "breaker-window", usize),
+            failure_ratio: value_t_or_exit!(matches, "breaker-threshold", f64),
+            cooldown: Duration::from_secs(value_t_or_exit!(matches, "breaker-cooldown", u64)),
+            maintenance_page: maintenance_page,
+        });
+    }
+    if matches.is_present("rate-limit") {
+        options.rate_limits = values_t_or_exit!(matches, "rate-limit", RateLimit);
+    }
+    options.shared_rate_limits = matches.is_present("rate-limit-redis");
+    options.in_flight_limit = limit(&matches, "max-in-flight", "max-in-flight-per-ip");
+    options.body_idle_timeout = seconds(&matches, "body-idle-timeout");
+    options.min_upload_rate = count(&matches, "min-upload-rate");
+    options.max_header_count = count(&matches, "max-header-count");
+    if let Some(mounts) = matches.values_of("static") {
+        for mount in mounts {
+            options.static_mounts.push(StaticMount::parse(mount).unwrap_or_else(|err| {
+                println!("Invalid --static {}: {}", mount, err);
+                std::process::exit(1);
+            }));
+        }
+    }
+
+    let factory = if matches.is_present("vhost") {
+        let vhosts = values_t_or_exit!(matches, "vhost", VirtualHost);
+        AsgiHttpServiceFactory::with_redis_virtual_hosts(reply_pumps, options, &vhosts)
+    } else {
+        AsgiHttpServiceFactory::<RedisChannelLayer>::new(reply_pumps, options)
+    };
+    let connection_limit = limit(&matches, "max-connections", "max-connections-per-ip");
+    let queue_length = value_t_or_exit!(matches, "connection-queue-length", usize);
+    let queue_timeout =
+        Duration::from_secs(value_t_or_exit!(matches, "connection-queue-timeout", u64));
+    let mut server = Server::new(factory)
+        .proxy_protocol(proxy_protocol)
+        .connection_limit(connection_limit, queue_length, queue_timeout)
+        .timeouts(ConnectionTimeouts {
+            header_timeout: seconds(&matches, "header-timeout"),
+            keep_alive_timeout: seconds(&matches, "keep-alive-timeout"),
+            max_header_size: count(&matches, "max-header-size"),
+        });
+    for listen in listens {
+        server = server.listen(listen);
+    }
+    if let Some(tls) = tls {
+        server = server.tls(tls);
+    }
     server.run().unwrap();
 }
+
+
+fn limit(matches: &clap::ArgMatches, total: &str, per_client: &str) -> Limit {
+    Limit {
+        total: count(matches, total),
+        per_client: count(matches, per_client),
+    }
+}
+
+fn count(matches: &clap::ArgMatches, name: &str) -> Option<usize> {
+    match matches.is_present(name) {
+        true => Some(value_t_or_exit!(matches, name, usize)),
+        false => None,
+    }
+}
+
+fn seconds(matches: &clap::ArgMatches, name: &str) -> Option<Duration> {
+    match matches.is_present(name) {
+        true => Some(Duration::from_secs(value_t_or_exit!(matches, name, u64))),
+        false => None,
+    }
+}
+
+
+fn validate_at_least_one(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(n) if n >= 1 => Ok(()),
+        _ => Err(format!("expected a whole number of at least 1, got {}", value)),
+    }
+}
+
+fn validate_ratio(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => Ok(()),
+        _ => Err(format!("expected a number greater than 0 and at most 1, got {}", value)),
+    }
+}
+
+
+fn parse_addr(option: &str, addr: &str) -> std::net::SocketAddr {
+    addr.parse().unwrap_or_else(|_| {
+        println!("Invalid {} address: {}", option, addr);
+        std::process::exit(1);
+    })
+}
@@ -10,6 +10,8 @@ pub struct Request<'a> {
     pub method: &'a str,
     pub scheme: &'a str,
     pub path: &'a str,
+    pub raw_path: Bytes<'a>,
+    pub root_path: &'a str,
     pub query_string: &'a str,
     // It'd be nice if headers didn't have to own their byte-strings. See #5.
     pub headers: Vec<(ByteBuf, ByteBuf)>,
@@ -17,7 +19,7 @@ pub struct Request<'a> {
     pub body_channel: Option<&'a str>,
     // Again, it would be nice if we didn't have to own a String, but hey-ho.
     pub client: Option<(String, u16)>,
-    pub server: (String, u16),
+    pub server: Option<(String, u16)>,
 }
 
 #[derive(Debug, Serialize)]
@@ -27,18 +29,63 @@ pub struct RequestBodyChunk<'a> {
     pub more_content: bool,
 }
 
+// Owned versions of the request messages, for workers receiving them rather than sending them.
+#[derive(Debug, Deserialize)]
+pub struct OwnedRequest {
+    pub reply_channel: String,
+    pub http_version: String,
+    pub method: String,
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    pub path: String,
+    #[serde(default)]
+    pub raw_path: Option<ByteBuf>,
+    #[serde(default)]
+    pub root_path: String,
+    #[serde(default)]
+    pub query_string: String,
+    pub headers: Vec<(ByteBuf, ByteBuf)>,
+    #[serde(default)]
+    pub body: ByteBuf,
+    #[serde(default)]
+    pub body_channel: Option<String>,
+    #[serde(default)]
+    pub client: Option<(String, u16)>,
+    #[serde(default)]
+    pub server: Option<(String, u16)>,
+}
+
+fn default_scheme() -> String {
+    "http".to_owned()
+}
 
 #[derive(Debug, Deserialize)]
+pub struct OwnedRequestBodyChunk {
+    #[serde(default)]
+    pub content: ByteBuf,
+    #[serde(default)]
+    pub closed: bool,
+    #[serde(default)]
+    pub more_content: bool,
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Response {
     pub status: u16,
     pub headers: Vec<(ByteBuf, ByteBuf)>,
     pub content: ByteBuf,
     pub more_content: bool,
+    // Trailing headers, only allowed on the last message of the response.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<(ByteBuf, ByteBuf)>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ResponseBodyChunk {
     #[serde(default)]
     pub content: ByteBuf,
     pub more_content: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<(ByteBuf, ByteBuf)>,
 }
@@ -0,0 +1,281 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::Headers;
+
+use cidr;
+use cidr::Cidr;
+use http::ConnectionInfo;
+
+
+// One proxy hop, as described by a forwarding header. Either may be missing if the proxy didn't
+// tell us, or told us something we couldn't understand (e.g. an obfuscated identifier).
+#[derive(Debug, PartialEq)]
+struct Hop {
+    client: Option<SocketAddr>,
+    proto: Option<&'static str>,
+}
+
+
+/// If the connection is from one of the trusted proxies, work out the real client address and
+/// scheme from the Forwarded, X-Forwarded-For, X-Real-IP and X-Forwarded-Proto headers.
+/// Connections on Unix domain sockets have no address to check, so are only trusted if
+/// trust_unix_sockets is set. Otherwise, the connection is returned as it is - anybody could have
+/// set those headers.
+pub fn resolve(trusted: &[Cidr],
+               trust_unix_sockets: bool,
+               headers: &Headers,
+               connection: &ConnectionInfo)
+               -> ConnectionInfo {
+    let mut connection = connection.clone();
+    let peer = match connection.client {
+        Some(peer) if cidr::contains_any(trusted, &peer.ip()) => Some(peer),
+        None if trust_unix_sockets => None,
+        _ => return connection,
+    };
+
+    // Prefer the standard Forwarded header, which describes both the client and scheme.
+    let forwarded = header_values(headers, "Forwarded");
+    if !forwarded.is_empty() {
+        let hops: Vec<Hop> = forwarded.iter().map(|element| parse_forwarded(element)).collect();
+        if let Some(hop) = pick_hop(trusted, hops) {
+            connection.client = hop.client.or(peer);
+            if let Some(proto) = hop.proto {
+                connection.scheme = proto;
+            }
+        }
+        return connection;
+    }
+
+    let forwarded_for = header_values(headers, "X-Forwarded-For");
+    let real_ip = header_values(headers, "X-Real-IP");
+    let client_hops = if !forwarded_for.is_empty() { forwarded_for } else { real_ip };
+    // Each proxy appends the scheme it received the request over alongside the address it received
+    // it from, so the schemes line up with the last of the addresses. Any extra schemes at the
+    // start were sent by the client, and hops without a scheme of their own get none.
+    let protos = header_values(headers, "X-Forwarded-Proto");
+    let hops: Vec<Hop> = client_hops.iter()
+        .enumerate()
+        .map(|(i, node)| {
+            Hop {
+                client: parse_node(node),
+                proto: (i + protos.len())
+                    .checked_sub(client_hops.len())
+                    .and_then(|i| protos.get(i))
+                    .and_then(|proto| parse_proto(proto)),
+            }
+        })
+        .collect();
+    match pick_hop(trusted, hops) {
+        Some(hop) => {
+            connection.client = hop.client.or(peer);
+            if let Some(proto) = hop.proto {
+                connection.scheme = proto;
+            }
+        }
+        // Without any addresses to go on, only the scheme our peer added can be trusted.
+        None => {
+            if let Some(proto) = protos.last().and_then(|proto| parse_proto(proto)) {
+                connection.scheme = proto;
+            }
+        }
+    }
+
+    connection
+}
+
+
+// Each proxy appends the address it received the request from, so the last hop was added by the
+// proxy we're talking to. Walk back through the hops until we find one that wasn't added by a
+// trusted proxy - anything before that could have been forged by the client.
+fn pick_hop(trusted: &[Cidr], hops: Vec<Hop>) -> Option<Hop> {
+    let mut hops = hops;
+    while let Some(hop) = hops.pop() {
+        let is_trusted = match hop.client {
+            Some(addr) => cidr::contains_any(trusted, &addr.ip()),
+            None => false,
+        };
+        if !is_trusted || hops.is_empty() {
+            return Some(hop);
+        }
+    }
+    None
+}
+
+
+// Splits every instance of a header into its comma-separated values.
+fn header_values(headers: &Headers, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    if let Some(raw) = headers.get_raw(name) {
+        for line in raw.iter() {
+            let line = String::from_utf8_lossy(line);
+            values.extend(line.split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_owned));
+        }
+    }
+    values
+}
+
+
+// Parses a single element of a Forwarded header, e.g. `for="[2001:db8::1]:4711";proto=https`.
+fn parse_forwarded(element: &str) -> Hop {
+    let mut hop = Hop {
+        client: None,
+        proto: None,
+    };
+    for pair in element.split(';') {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().trim_matches('"');
+        match key.as_ref() {
+            "for" => hop.client = parse_node(value),
+            "proto" => hop.proto = parse_proto(value),
+            _ => {}
+        }
+    }
+    hop
+}
+
+// Parses a node: an IPv4 address or a bracketed IPv6 address, either with an optional port. We
+// also accept unbracketed IPv6 addresses, as some proxies send them in X-Forwarded-For.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.trim_left_matches('[').trim_right_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+fn parse_proto(proto: &str) -> Option<&'static str> {
+    match proto.to_lowercase().as_ref() {
+        "http" => Some("http"),
+        "https" => Some("https"),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_forwarded, parse_node, resolve, Hop};
+
+    use hyper::Headers;
+
+    use cidr::Cidr;
+    use http::ConnectionInfo;
+
+    fn connection(client: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            scheme: "http",
+            server: Some("10.0.0.1:8000".parse().unwrap()),
+            client: Some(client.parse().unwrap()),
+            activity: None,
+        }
+    }
+
+    fn unix_connection() -> ConnectionInfo {
+        ConnectionInfo {
+            scheme: "http",
+            server: None,
+            client: None,
+            activity: None,
+        }
+    }
+
+    fn trusted() -> Vec<Cidr> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.60"), Some("192.0.2.60:0".parse().unwrap()));
+        assert_eq!(parse_node("192.0.2.60:4711"), Some("192.0.2.60:4711".parse().unwrap()));
+        assert_eq!(parse_node("[2001:db8::1]:4711"), Some("[2001:db8::1]:4711".parse().unwrap()));
+        assert_eq!(parse_node("[2001:db8::1]"), Some("[2001:db8::1]:0".parse().unwrap()));
+        assert_eq!(parse_node("2001:db8::1"), Some("[2001:db8::1]:0".parse().unwrap()));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_parse_forwarded() {
+        assert_eq!(parse_forwarded("for=\"[2001:db8::1]:4711\";Proto=HTTPS;by=10.0.0.2"),
+                   Hop {
+                       client: Some("[2001:db8::1]:4711".parse().unwrap()),
+                       proto: Some("https"),
+                   });
+    }
+
+    #[test]
+    fn test_resolve_untrusted_peer() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", "1.2.3.4");
+        headers.set_raw("X-Forwarded-Proto", "https");
+        let resolved = resolve(&trusted(), false, &headers, &connection("8.8.8.8:1234"));
+        assert_eq!(resolved.client, Some("8.8.8.8:1234".parse().unwrap()));
+        assert_eq!(resolved.scheme, "http");
+    }
+
+    #[test]
+    fn test_resolve_x_forwarded_for() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 10.0.0.3");
+        headers.set_raw("X-Forwarded-Proto", "https, http");
+        let resolved = resolve(&trusted(), false, &headers, &connection("10.0.0.2:1234"));
+        assert_eq!(resolved.client, Some("1.2.3.4:0".parse().unwrap()));
+        assert_eq!(resolved.scheme, "https");
+    }
+
+    #[test]
+    fn test_resolve_forged_x_forwarded_proto() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", "1.2.3.4");
+        headers.set_raw("X-Forwarded-Proto", "https, http");
+        let resolved = resolve(&trusted(), false, &headers, &connection("10.0.0.2:1234"));
+        assert_eq!(resolved.client, Some("1.2.3.4:0".parse().unwrap()));
+        assert_eq!(resolved.scheme, "http");
+
+        headers.remove_raw("X-Forwarded-For");
+        let resolved = resolve(&trusted(), false, &headers, &connection("10.0.0.2:1234"));
+        assert_eq!(resolved.scheme, "http");
+    }
+
+    #[test]
+    fn test_resolve_x_real_ip() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Real-IP", "1.2.3.4");
+        let resolved = resolve(&trusted(), false, &headers, &connection("10.0.0.2:1234"));
+        assert_eq!(resolved.client, Some("1.2.3.4:0".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_resolve_forwarded() {
+        let mut headers = Headers::new();
+        headers.set_raw("Forwarded", "for=1.2.3.4;proto=https, for=10.0.0.3");
+        headers.set_raw("X-Forwarded-For", "6.6.6.6");
+        let resolved = resolve(&trusted(), false, &headers, &connection("10.0.0.2:1234"));
+        assert_eq!(resolved.client, Some("1.2.3.4:0".parse().unwrap()));
+        assert_eq!(resolved.scheme, "https");
+    }
+
+    #[test]
+    fn test_resolve_unix_socket() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", "1.2.3.4");
+        headers.set_raw("X-Forwarded-Proto", "https");
+
+        let resolved = resolve(&trusted(), false, &headers, &unix_connection());
+        assert_eq!(resolved.client, None);
+        assert_eq!(resolved.scheme, "http");
+
+        let resolved = resolve(&trusted(), true, &headers, &unix_connection());
+        assert_eq!(resolved.client, Some("1.2.3.4:0".parse().unwrap()));
+        assert_eq!(resolved.scheme, "https");
+
+        // Trusting Unix domain sockets doesn't make us trust anybody else.
+        let resolved = resolve(&trusted(), true, &headers, &connection("8.8.8.8:1234"));
+        assert_eq!(resolved.client, Some("8.8.8.8:1234".parse().unwrap()));
+        assert_eq!(resolved.scheme, "http");
+    }
+}
@@ -0,0 +1,336 @@
+use std;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use futures::{Async, Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+
+// The longest a v1 header can be, including its trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+const V1_PREFIX: &'static [u8] = b"PROXY ";
+
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// The signature, version/command, family/protocol and address length.
+const V2_HEADER_LEN: usize = 16;
+
+/// How long a trusted proxy has to send its header when no --header-timeout is set. Proxies send
+/// it as soon as they connect, so a connection without one is stuck and would otherwise be held
+/// open forever.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+
+/// The original endpoints of a connection, as told to us by a proxy. These are None if the proxy
+/// didn't know them (e.g. for its own health checks), in which case we should use the endpoints
+/// of the connection itself.
+#[derive(Debug, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    fn unknown() -> Self {
+        ProxyHeader {
+            source: None,
+            destination: None,
+        }
+    }
+}
+
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid PROXY protocol header: {}", msg))
+}
+
+
+/// Tries to parse a PROXY protocol v1 or v2 header from the start of buf. Returns the header and
+/// its length if we have all of it, or None if we need to read more before we can tell.
+fn parse(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        Ok(None)
+    } else {
+        Err(invalid("missing signature"))
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return Err(invalid("v1 header too long")),
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(invalid("v1 header too long")),
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| invalid("not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let header = match parts[0] {
+        // Anything may follow UNKNOWN, and we should ignore it.
+        "UNKNOWN" => ProxyHeader::unknown(),
+        "TCP4" | "TCP6" if parts.len() == 5 => {
+            let source: IpAddr = parts[1].parse().map_err(|_| invalid("bad source address"))?;
+            let dest: IpAddr = parts[2].parse().map_err(|_| invalid("bad destination address"))?;
+            let source_port: u16 = parts[3].parse().map_err(|_| invalid("bad source port"))?;
+            let dest_port: u16 = parts[4].parse().map_err(|_| invalid("bad destination port"))?;
+            ProxyHeader {
+                source: Some(SocketAddr::new(source, source_port)),
+                destination: Some(SocketAddr::new(dest, dest_port)),
+            }
+        }
+        _ => return Err(invalid("unsupported v1 protocol")),
+    };
+    Ok(Some((header, end + 2)))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13];
+    let len = ((buf[14] as usize) << 8) | buf[15] as usize;
+    if version != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    if buf.len() < V2_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let addrs = &buf[V2_HEADER_LEN..V2_HEADER_LEN + len];
+
+    let header = match (command, family) {
+        // LOCAL connections are made by the proxy itself, so have no original endpoints.
+        (0x0, _) => ProxyHeader::unknown(),
+        // TCP or UDP over IPv4.
+        (0x1, 0x11) | (0x1, 0x12) => {
+            if addrs.len() < 12 {
+                return Err(invalid("IPv4 addresses too short"));
+            }
+            let source = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let dest = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+            ProxyHeader {
+                source: Some(SocketAddr::new(IpAddr::V4(source), read_port(&addrs[8..10]))),
+                destination: Some(SocketAddr::new(IpAddr::V4(dest), read_port(&addrs[10..12]))),
+            }
+        }
+        // TCP or UDP over IPv6.
+        (0x1, 0x21) | (0x1, 0x22) => {
+            if addrs.len() < 36 {
+                return Err(invalid("IPv6 addresses too short"));
+            }
+            let source = Ipv6Addr::from(read_ipv6(&addrs[0..16]));
+            let dest = Ipv6Addr::from(read_ipv6(&addrs[16..32]));
+            ProxyHeader {
+                source: Some(SocketAddr::new(IpAddr::V6(source), read_port(&addrs[32..34]))),
+                destination: Some(SocketAddr::new(IpAddr::V6(dest), read_port(&addrs[34..36]))),
+            }
+        }
+        // We must accept connections from other families (e.g. Unix sockets), but there's
+        // nothing we can sensibly report as their address.
+        (0x1, _) => ProxyHeader::unknown(),
+        _ => return Err(invalid("unsupported command")),
+    };
+    Ok(Some((header, V2_HEADER_LEN + len)))
+}
+
+fn read_port(buf: &[u8]) -> u16 {
+    ((buf[0] as u16) << 8) | buf[1] as u16
+}
+
+fn read_ipv6(buf: &[u8]) -> [u8; 16] {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(buf);
+    octets
+}
+
+
+/// Reads a PROXY protocol header from the start of a stream. Resolves to the header and the
+/// stream, which will go on to return anything we read beyond the header.
+pub fn read_header<S>(stream: S) -> ReadHeader<S>
+    where S: AsyncRead + AsyncWrite
+{
+    ReadHeader {
+        stream: Some(stream),
+        buf: Vec::new(),
+    }
+}
+
+pub struct ReadHeader<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S> Future for ReadHeader<S>
+    where S: AsyncRead + AsyncWrite
+{
+    type Item = (ProxyHeader, Rewind<S>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some((header, len)) = parse(&self.buf)? {
+                let stream = self.stream.take().expect("Polled ReadHeader after completion");
+                let rest = self.buf[len..].to_vec();
+                return Ok(Async::Ready((header, Rewind::new(rest, stream))));
+            }
+
+            let mut chunk = [0; 512];
+            let stream = self.stream.as_mut().expect("Polled ReadHeader after completion");
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                   "Connection closed before PROXY header")),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+
+/// A stream which returns some bytes we've already read from it before reading any more.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    stream: S,
+}
+
+impl<S> Rewind<S> {
+    fn new(prefix: Vec<u8>, stream: S) -> Self {
+        Rewind {
+            prefix: prefix,
+            stream: stream,
+        }
+    }
+}
+
+impl<S> Read for Rewind<S>
+    where S: Read
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.stream.read(buf);
+        }
+        let n = std::cmp::min(buf.len(), self.prefix.len());
+        buf[..n].copy_from_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<S> Write for Rewind<S>
+    where S: Write
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S> AsyncRead for Rewind<S> where S: AsyncRead {}
+
+impl<S> AsyncWrite for Rewind<S>
+    where S: AsyncWrite
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ProxyHeader};
+
+    #[test]
+    fn test_parse_v1() {
+        let buf = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (header, len) = parse(buf).unwrap().unwrap();
+        assert_eq!(header,
+                   ProxyHeader {
+                       source: Some("192.168.0.1:56324".parse().unwrap()),
+                       destination: Some("10.0.0.1:443".parse().unwrap()),
+                   });
+        assert_eq!(&buf[len..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_parse_v1_tcp6() {
+        let buf = b"PROXY TCP6 fd00::1 fd00::2 56324 443\r\n";
+        let (header, _) = parse(buf).unwrap().unwrap();
+        assert_eq!(header.source, Some("[fd00::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_v1_unknown() {
+        let (header, len) = parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(header, ProxyHeader::unknown());
+        assert_eq!(len, 15);
+    }
+
+    #[test]
+    fn test_parse_v1_incomplete() {
+        assert!(parse(b"PRO").unwrap().is_none());
+        assert!(parse(b"PROXY TCP4 192.168").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_v1_invalid() {
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 nonsense 10.0.0.1 56324 443\r\n").is_err());
+        assert!(parse(&[b'A'; 200]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2_ipv4() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        buf.extend_from_slice(&[192, 168, 0, 1, 10, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        buf.extend_from_slice(b"GET");
+        let (header, len) = parse(&buf).unwrap().unwrap();
+        assert_eq!(header,
+                   ProxyHeader {
+                       source: Some("192.168.0.1:56324".parse().unwrap()),
+                       destination: Some("10.0.0.1:443".parse().unwrap()),
+                   });
+        assert_eq!(&buf[len..], b"GET");
+    }
+
+    #[test]
+    fn test_parse_v2_local() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let (header, len) = parse(&buf).unwrap().unwrap();
+        assert_eq!(header, ProxyHeader::unknown());
+        assert_eq!(len, 16);
+    }
+
+    #[test]
+    fn test_parse_v2_incomplete() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c, 192, 168]);
+        assert!(parse(&buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_v2_short_addresses() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 192, 168, 0, 1]);
+        assert!(parse(&buf).is_err());
+
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x21, 0x21, 0x00, 0x0c]);
+        buf.extend_from_slice(&[0; 12]);
+        assert!(parse(&buf).is_err());
+    }
+}
@@ -0,0 +1,494 @@
+use std;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::Headers;
+use r2d2;
+
+use channels::ChannelLayer;
+use routing;
+
+
+// Forget local buckets which have refilled this often, as they're no different to new ones.
+const SWEEP_INTERVAL_SECS: u64 = 10;
+// The most local buckets we'll keep. Past this, we forget the least recently used, even if that
+// lets their clients off early.
+const MAX_BUCKETS: usize = 100000;
+// The longest bucket key we'll use. Anything longer, e.g. an outsized API key header, is cut
+// short, so that clients can't make us store keys of any size.
+const MAX_KEY_LEN: usize = 256;
+
+
+/// What requests a rate limit counts together.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RateLimitKey {
+    /// Each client IP address gets its own bucket.
+    ClientIp,
+    /// Each value of the header (e.g. an API key) gets its own bucket. Requests without it
+    /// aren't limited.
+    Header(String),
+    /// All requests under the path prefix share a bucket.
+    PathPrefix(String),
+}
+
+
+/// A token bucket: requests may be made at `rate` per second on average, with bursts of up to
+/// `burst` requests at once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub key: RateLimitKey,
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// Parses a `--rate-limit` value of the form `KEY:RATE/UNIT[,burst=N]`, where KEY is `ip`,
+/// `header=NAME` or `path=PREFIX` and UNIT is `s`, `m` or `h`, e.g. `ip:10/s,burst=20`. The
+/// burst defaults to the number of requests allowed per unit.
+impl FromStr for RateLimit {
+    type Err = RateLimitParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let limit = options.next().unwrap_or("");
+        let index = limit.rfind(':').ok_or(RateLimitParseError::Invalid)?;
+        let (key, rate) = (&limit[..index], &limit[index + 1..]);
+
+        let key = match key {
+            "ip" => RateLimitKey::ClientIp,
+            _ if key.starts_with("header=") && key.len() > 7 => {
+                RateLimitKey::Header(key[7..].to_owned())
+            }
+            _ if key.starts_with("path=/") => RateLimitKey::PathPrefix(key[5..].to_owned()),
+            _ => return Err(RateLimitParseError::Invalid),
+        };
+
+        let mut parts = rate.splitn(2, '/');
+        let count: f64 = parts.next()
+            .and_then(|count| count.parse().ok())
+            .ok_or(RateLimitParseError::Invalid)?;
+        let per_secs = match parts.next() {
+            Some("s") => 1.0,
+            Some("m") => 60.0,
+            Some("h") => 3600.0,
+            _ => return Err(RateLimitParseError::Invalid),
+        };
+        if count <= 0.0 {
+            return Err(RateLimitParseError::Invalid);
+        }
+
+        let mut rate_limit = RateLimit {
+            key: key,
+            rate: count / per_secs,
+            burst: count,
+        };
+        for option in options {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next().unwrap_or("").trim(), parts.next()) {
+                ("burst", Some(burst)) => {
+                    rate_limit.burst = burst.parse().map_err(|_| RateLimitParseError::Invalid)?;
+                    if rate_limit.burst < 1.0 {
+                        return Err(RateLimitParseError::Invalid);
+                    }
+                }
+                (key, _) => return Err(RateLimitParseError::UnknownOption(key.to_owned())),
+            }
+        }
+        Ok(rate_limit)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = limit.burst.min(self.tokens + elapsed * limit.rate);
+        self.updated = now;
+    }
+
+    // How long until there'll be a token to take, if there isn't one now.
+    fn wait(&self, limit: &RateLimit) -> Option<Duration> {
+        match self.tokens >= 1.0 {
+            true => None,
+            false => Some(secs_to_duration((1.0 - self.tokens) / limit.rate)),
+        }
+    }
+
+    // Takes a token if there's one to take. Otherwise, returns how long until there will be.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if let Some(wait) = self.wait(limit) {
+            return Err(wait);
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+// Cuts a bucket key down to at most MAX_KEY_LEN bytes, without splitting a character.
+fn truncate_key(key: &str) -> &str {
+    let mut len = std::cmp::min(key.len(), MAX_KEY_LEN);
+    while !key.is_char_boundary(len) {
+        len -= 1;
+    }
+    &key[..len]
+}
+
+fn secs_to_duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+
+/// A bucket belonging to one of a RateLimiter's limits: the limit's index, and the key of the
+/// bucket within it (e.g. the client's address).
+pub type BucketKey = (usize, String);
+
+
+struct LocalBuckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    swept: Instant,
+}
+
+impl LocalBuckets {
+    fn new() -> Self {
+        LocalBuckets {
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+        }
+    }
+
+    // The bucket with the key, making room for it if it's new.
+    fn get(&mut self, key: &BucketKey, limit: &RateLimit, now: Instant) -> &mut Bucket {
+        if !self.buckets.contains_key(key) && self.buckets.len() >= MAX_BUCKETS {
+            self.evict_oldest(MAX_BUCKETS / 10);
+        }
+        self.buckets.entry(key.clone()).or_insert_with(|| Bucket::full(limit, now))
+    }
+
+    fn sweep(&mut self, limits: &[RateLimit], now: Instant) {
+        self.buckets.retain(|&(index, _), bucket| {
+            let limit = &limits[index];
+            let mut bucket = *bucket;
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst
+        });
+        self.swept = now;
+    }
+
+    // Forgets at least `count` of the least recently used buckets. We do several at once, so
+    // that we don't have to look for the oldest on every request once we're full.
+    fn evict_oldest(&mut self, count: usize) {
+        let mut updated: Vec<Instant> =
+            self.buckets.values().map(|bucket| bucket.updated).collect();
+        updated.sort();
+        if let Some(&cutoff) = updated.get(count.saturating_sub(1)) {
+            self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+    }
+}
+
+enum Store {
+    Local(Mutex<LocalBuckets>),
+    // In the channel layer of the application the request is for.
+    Shared,
+}
+
+
+/// Enforces rate limits, keeping the state of their buckets either in memory or in the channel
+/// layer, so that they can be shared by several server instances.
+pub struct RateLimiter {
+    limits: Vec<RateLimit>,
+    store: Store,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Vec<RateLimit>) -> Self {
+        RateLimiter {
+            limits: limits,
+            store: Store::Local(Mutex::new(LocalBuckets::new())),
+        }
+    }
+
+    /// Keeps the buckets in the channel layer, e.g. Redis, so that every server instance using
+    /// it shares them.
+    pub fn shared(limits: Vec<RateLimit>) -> Self {
+        RateLimiter {
+            limits: limits,
+            store: Store::Shared,
+        }
+    }
+
+    /// The buckets a request must take a token from. Quick to work out, so that we can do it
+    /// before handing the request to the thread-pool.
+    pub fn buckets(&self,
+                   client: Option<IpAddr>,
+                   headers: &Headers,
+                   path: &str)
+                   -> Vec<BucketKey> {
+        self.limits
+            .iter()
+            .enumerate()
+            .filter_map(|(i, limit)| {
+                let key = match limit.key {
+                    RateLimitKey::ClientIp => client.map(|ip| ip.to_string()),
+                    RateLimitKey::Header(ref name) => {
+                        headers.get_raw(name)
+                            .and_then(|raw| raw.one())
+                            .map(|value| String::from_utf8_lossy(value).into_owned())
+                    }
+                    RateLimitKey::PathPrefix(ref prefix) => {
+                        match routing::has_path_prefix(path, prefix) {
+                            true => Some(String::new()),
+                            false => None,
+                        }
+                    }
+                };
+                key.map(|key| (i, key))
+            })
+            .collect()
+    }
+
+    /// Takes a token from each bucket if they all have one, or none if any are empty, in which
+    /// case returns how long the client should wait before trying again. Shared buckets are kept
+    /// in a channel layer from the pool, so this may block. Without a pool, there's no limit on
+    /// them.
+    pub fn take<C>(&self,
+                   buckets: &[BucketKey],
+                   channel_pool: Option<&r2d2::Pool<C::Manager>>)
+                   -> Result<(), Duration>
+        where C: ChannelLayer
+    {
+        let truncated: Vec<BucketKey> = buckets.iter()
+            .map(|&(index, ref key)| (index, truncate_key(key).to_owned()))
+            .collect();
+        let buckets = &truncated;
+        match self.store {
+            Store::Local(ref local) => {
+                let mut local = local.lock().unwrap();
+                let now = Instant::now();
+                if now.duration_since(local.swept) >= Duration::from_secs(SWEEP_INTERVAL_SECS) {
+                    local.sweep(&self.limits, now);
+                }
+                let mut wait = None;
+                for key in buckets {
+                    let limit = &self.limits[key.0];
+                    let bucket = local.get(key, limit, now);
+                    bucket.refill(limit, now);
+                    wait = wait.max(bucket.wait(limit));
+                }
+                if let Some(wait) = wait {
+                    return Err(wait);
+                }
+                for key in buckets {
+                    local.get(key, &self.limits[key.0], now).tokens -= 1.0;
+                }
+                Ok(())
+            }
+            Store::Shared => {
+                let channel_pool = match channel_pool {
+                    Some(channel_pool) => channel_pool,
+                    None => return Ok(()),
+                };
+                let buckets: Vec<(String, f64, f64)> = buckets.iter()
+                    .map(|&(index, ref key)| {
+                        let limit = &self.limits[index];
+                        (format!("{}:{}", index, key), limit.rate, limit.burst)
+                    })
+                    .collect();
+                let wait = channel_pool.get()
+                    .map_err(|err| err.to_string())
+                    .and_then(|channel_layer| {
+                        channel_layer.take_tokens(&buckets).map_err(|err| err.to_string())
+                    });
+                match wait {
+                    Ok(Some(wait)) => Err(wait),
+                    Ok(None) => Ok(()),
+                    // Rather than refuse every request while the channel layer is unavailable,
+                    // let them all through.
+                    Err(err) => {
+                        println!("Failed to check rate limits: {}", err);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitParseError {
+    Invalid,
+    UnknownOption(String),
+}
+
+impl std::fmt::Display for RateLimitParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            RateLimitParseError::Invalid => {
+                write!(f,
+                       "Rate limits must be of the form KEY:RATE/UNIT[,burst=N], where KEY is \
+                        ip, header=NAME or path=PREFIX and UNIT is s, m or h")
+            }
+            RateLimitParseError::UnknownOption(ref option) => {
+                write!(f, "Unknown rate limit option: {}", option)
+            }
+        }
+    }
+}
+
+impl Error for RateLimitParseError {
+    fn description(&self) -> &str {
+        match *self {
+            RateLimitParseError::Invalid => "Invalid rate limit",
+            RateLimitParseError::UnknownOption(_) => "Unknown rate limit option",
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{truncate_key, Bucket, LocalBuckets, RateLimit, RateLimitKey, RateLimitParseError,
+                RateLimiter, MAX_KEY_LEN};
+
+    use std::time::{Duration, Instant};
+
+    use hyper::Headers;
+
+    use channels::RedisChannelLayer;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("ip:10/s,burst=20".parse(),
+                   Ok(RateLimit {
+                       key: RateLimitKey::ClientIp,
+                       rate: 10.0,
+                       burst: 20.0,
+                   }));
+        assert_eq!("header=X-Api-Key:120/m".parse(),
+                   Ok(RateLimit {
+                       key: RateLimitKey::Header("X-Api-Key".to_owned()),
+                       rate: 2.0,
+                       burst: 120.0,
+                   }));
+        assert_eq!("path=/api:3600/h".parse::<RateLimit>().unwrap().key,
+                   RateLimitKey::PathPrefix("/api".to_owned()));
+
+        assert_eq!("ip:10".parse::<RateLimit>(), Err(RateLimitParseError::Invalid));
+        assert_eq!("ip:10/d".parse::<RateLimit>(), Err(RateLimitParseError::Invalid));
+        assert_eq!("cookie:10/s".parse::<RateLimit>(), Err(RateLimitParseError::Invalid));
+        assert_eq!("ip:10/s,window=5".parse::<RateLimit>(),
+                   Err(RateLimitParseError::UnknownOption("window".to_owned())));
+    }
+
+    #[test]
+    fn test_bucket() {
+        let limit: RateLimit = "ip:2/s".parse().unwrap();
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limit, start);
+        assert!(bucket.take(&limit, start).is_ok());
+        assert!(bucket.take(&limit, start).is_ok());
+        assert_eq!(bucket.take(&limit, start), Err(Duration::from_millis(500)));
+        assert!(bucket.take(&limit, start + Duration::from_millis(500)).is_ok());
+        // We never refill beyond the burst.
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(&limit, later).is_ok());
+        assert!(bucket.take(&limit, later).is_ok());
+        assert!(bucket.take(&limit, later).is_err());
+    }
+
+    #[test]
+    fn test_local_buckets() {
+        let limit: RateLimit = "ip:1/s".parse().unwrap();
+        let limits = vec![limit.clone()];
+        let start = Instant::now();
+        let mut local = LocalBuckets::new();
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            let now = start + Duration::from_millis(i as u64 * 100);
+            let key = (0, key.to_string());
+            assert!(local.get(&key, &limit, now).take(&limit, now).is_ok());
+        }
+
+        // Only buckets which haven't refilled are worth keeping.
+        local.sweep(&limits, start + Duration::from_millis(1050));
+        assert_eq!(local.buckets.len(), 2);
+
+        local.evict_oldest(1);
+        assert_eq!(local.buckets.keys().collect::<Vec<_>>(), vec![&(0, "c".to_owned())]);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(vec!["header=X-Api-Key:1/m".parse().unwrap(),
+                                            "path=/api:100/s".parse().unwrap()]);
+        let mut headers = Headers::new();
+        assert_eq!(limiter.buckets(None, &headers, "/"), vec![]);
+        headers.set_raw("X-Api-Key", "secret");
+        let buckets = limiter.buckets(None, &headers, "/api/users");
+        assert_eq!(buckets, vec![(0, "secret".to_owned()), (1, String::new())]);
+        assert_eq!(limiter.buckets(None, &headers, "/apiary"), vec![(0, "secret".to_owned())]);
+
+        assert!(limiter.take::<RedisChannelLayer>(&buckets, None).is_ok());
+        assert!(limiter.take::<RedisChannelLayer>(&buckets, None).is_err());
+        let buckets = limiter.buckets(None, &Headers::new(), "/api");
+        assert!(limiter.take::<RedisChannelLayer>(&buckets, None).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_takes_all_or_nothing() {
+        let limiter = RateLimiter::new(vec!["path=/api:1/m".parse().unwrap(),
+                                            "ip:2/m".parse().unwrap()]);
+        let client = Some("192.0.2.1".parse().unwrap());
+        let headers = Headers::new();
+        let api = limiter.buckets(client, &headers, "/api/users");
+        assert!(limiter.take::<RedisChannelLayer>(&api, None).is_ok());
+        assert!(limiter.take::<RedisChannelLayer>(&api, None).is_err());
+
+        // Being refused by the path's limit didn't use up the client's.
+        let other = limiter.buckets(client, &headers, "/other");
+        assert!(limiter.take::<RedisChannelLayer>(&other, None).is_ok());
+        assert!(limiter.take::<RedisChannelLayer>(&other, None).is_err());
+    }
+
+    #[test]
+    fn test_truncate_key() {
+        assert_eq!(truncate_key("secret"), "secret");
+        let long = "a".repeat(MAX_KEY_LEN + 10);
+        assert_eq!(truncate_key(&long).len(), MAX_KEY_LEN);
+        // Don't split a multi-byte character.
+        let long = format!("{}☃", "a".repeat(MAX_KEY_LEN - 1));
+        assert_eq!(truncate_key(&long), &long[..MAX_KEY_LEN - 1]);
+    }
+
+    #[test]
+    fn test_rate_limiter_truncates_keys() {
+        let limiter = RateLimiter::new(vec!["header=X-Api-Key:1/m".parse().unwrap()]);
+        let mut headers = Headers::new();
+        headers.set_raw("X-Api-Key", format!("{}a", "k".repeat(MAX_KEY_LEN)));
+        let buckets = limiter.buckets(None, &headers, "/");
+        assert!(limiter.take::<RedisChannelLayer>(&buckets, None).is_ok());
+
+        // Values which only differ past the cut share a bucket.
+        headers.set_raw("X-Api-Key", format!("{}b", "k".repeat(MAX_KEY_LEN)));
+        let buckets = limiter.buckets(None, &headers, "/");
+        assert!(limiter.take::<RedisChannelLayer>(&buckets, None).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::ascii::AsciiExt;
+
+use hyper::Headers;
+use rand::{thread_rng, Rng};
+
+
+/// The header we take request IDs from, pass them to the application in and echo them back in.
+pub const HEADER: &'static str = "X-Request-ID";
+
+// We only accept IDs that are safe to put in our logs as they are, and not absurdly long.
+const MAX_LENGTH: usize = 200;
+
+
+/// The ID for a request: the one it came with in X-Request-ID if that's sensible, e.g. from a
+/// load balancer, or a new one otherwise.
+pub fn for_request(headers: &Headers) -> String {
+    let given = headers.get_raw(HEADER)
+        .and_then(|raw| raw.one())
+        .and_then(|id| String::from_utf8(id.to_vec()).ok());
+    match given {
+        Some(id) if is_valid(&id) => id,
+        _ => generate(),
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH &&
+    id.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || "-_.:+/=".contains(c)))
+}
+
+fn generate() -> String {
+    let mut rng = thread_rng();
+    format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{for_request, HEADER};
+
+    use hyper::Headers;
+
+    #[test]
+    fn test_for_request() {
+        let mut headers = Headers::new();
+        headers.set_raw(HEADER, "3f2b8c1e-lb-42");
+        assert_eq!(for_request(&headers), "3f2b8c1e-lb-42");
+
+        let generated = for_request(&Headers::new());
+        assert_eq!(generated.len(), 32);
+        assert!(generated != for_request(&Headers::new()));
+
+        headers.set_raw(HEADER, "bad id\nforged log line");
+        assert_eq!(for_request(&headers).len(), 32);
+        headers.set_raw(HEADER, "x".repeat(201));
+        assert_eq!(for_request(&headers).len(), 32);
+    }
+}
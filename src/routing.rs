@@ -0,0 +1,219 @@
+use std;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+use channels;
+
+
+/// The channel we send requests on when they don't match any route.
+pub const DEFAULT_CHANNEL: &'static str = "http.request";
+
+
+/// Sends requests for a host and path prefix to their own channel, so that they can be served by
+/// a separate pool of workers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    /// The host this route applies to, or None for any host. A leading "*." matches any
+    /// subdomain.
+    pub host: Option<String>,
+    pub path_prefix: String,
+    pub channel: String,
+    /// Overrides HttpOptions::request_timeout for this route.
+    pub timeout: Option<Duration>,
+    /// Overrides HttpOptions::max_body_size for this route.
+    pub max_body_size: Option<usize>,
+}
+
+impl Route {
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        match (&self.host, host) {
+            (&None, _) => true,
+            (&Some(_), None) => false,
+            (&Some(ref pattern), Some(host)) => {
+                match pattern.starts_with("*.") {
+                    true => host.ends_with(&pattern[1..]),
+                    false => host == pattern,
+                }
+            }
+        }
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        has_path_prefix(path, &self.path_prefix)
+    }
+}
+
+/// Whether the path is under the prefix. Prefixes only match whole path segments, so /api
+/// doesn't match /apiary.
+pub fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_right_matches('/');
+    path.starts_with(prefix) &&
+    (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+}
+
+/// Parses a `--route` value of the form `[HOST]/PREFIX=CHANNEL[,timeout=SECS][,max-body=BYTES]`,
+/// e.g. `api.example.com/v2=http.request.api,timeout=30`.
+impl FromStr for Route {
+    type Err = RouteParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let target = options.next().unwrap_or("");
+        let index = target.find('=').ok_or(RouteParseError::Invalid)?;
+        let (location, channel) = (&target[..index], &target[index + 1..]);
+        let slash = location.find('/').ok_or(RouteParseError::Invalid)?;
+        if channel.is_empty() {
+            return Err(RouteParseError::Invalid);
+        }
+        if channels::validate_channel_name(channel).is_err() {
+            return Err(RouteParseError::InvalidChannel(channel.to_owned()));
+        }
+
+        let mut route = Route {
+            host: match slash {
+                0 => None,
+                _ => Some(location[..slash].to_lowercase()),
+            },
+            path_prefix: location[slash..].to_owned(),
+            channel: channel.to_owned(),
+            timeout: None,
+            max_body_size: None,
+        };
+        for option in options {
+            let mut parts = option.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "timeout" => {
+                    let secs = value.parse().map_err(|_| RouteParseError::Invalid)?;
+                    route.timeout = Some(Duration::from_secs(secs));
+                }
+                "max-body" => {
+                    let max_body_size = value.parse().map_err(|_| RouteParseError::Invalid)?;
+                    route.max_body_size = Some(max_body_size);
+                }
+                _ => return Err(RouteParseError::UnknownOption(key.to_owned())),
+            }
+        }
+        Ok(route)
+    }
+}
+
+
+/// The most specific route for a request: routes for a particular host are preferred over those
+/// for any host, then longer prefixes over shorter ones. Returns None if no route matches, in
+/// which case the request goes to DEFAULT_CHANNEL.
+pub fn select<'a>(routes: &'a [Route], host: Option<&str>, path: &str) -> Option<&'a Route> {
+    let host = host.map(normalize_host);
+    routes.iter()
+        .filter(|route| route.matches_host(host.as_ref().map(String::as_ref)))
+        .filter(|route| route.matches_path(path))
+        .max_by_key(|route| {
+            (route.host.is_some(), route.path_prefix.trim_right_matches('/').len())
+        })
+}
+
+/// Lowercases a Host header's value and removes its port, if it has one.
+pub fn normalize_host(host: &str) -> String {
+    strip_port(host).to_lowercase()
+}
+
+fn strip_port(host: &str) -> &str {
+    // Don't mistake the colons in a bracketed IPv6 address for a port.
+    match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum RouteParseError {
+    Invalid,
+    InvalidChannel(String),
+    UnknownOption(String),
+}
+
+impl std::fmt::Display for RouteParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            RouteParseError::Invalid => {
+                write!(f, "Routes must be of the form [HOST]/PREFIX=CHANNEL[,OPTION=VALUE]")
+            }
+            RouteParseError::InvalidChannel(ref channel) => {
+                write!(f, "Invalid channel name: {}", channel)
+            }
+            RouteParseError::UnknownOption(ref option) => {
+                write!(f, "Unknown route option: {}", option)
+            }
+        }
+    }
+}
+
+impl Error for RouteParseError {
+    fn description(&self) -> &str {
+        match *self {
+            RouteParseError::Invalid => "Invalid route",
+            RouteParseError::InvalidChannel(_) => "Invalid channel name",
+            RouteParseError::UnknownOption(_) => "Unknown route option",
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{select, Route, RouteParseError};
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("api.example.com/v2=http.request.api,timeout=30,max-body=1024".parse(),
+                   Ok(Route {
+                       host: Some("api.example.com".to_owned()),
+                       path_prefix: "/v2".to_owned(),
+                       channel: "http.request.api".to_owned(),
+                       timeout: Some(Duration::from_secs(30)),
+                       max_body_size: Some(1024),
+                   }));
+        let route: Route = "/=http.request.default".parse().unwrap();
+        assert_eq!(route.host, None);
+        assert_eq!(route.path_prefix, "/");
+
+        assert_eq!("http.request.api".parse::<Route>(), Err(RouteParseError::Invalid));
+        assert_eq!("example.com=http.request.api".parse::<Route>(),
+                   Err(RouteParseError::Invalid));
+        assert_eq!("/api=".parse::<Route>(), Err(RouteParseError::Invalid));
+        assert_eq!("/api=http request".parse::<Route>(),
+                   Err(RouteParseError::InvalidChannel("http request".to_owned())));
+        assert_eq!("/api=http.request.api,retries=3".parse::<Route>(),
+                   Err(RouteParseError::UnknownOption("retries".to_owned())));
+    }
+
+    #[test]
+    fn test_select() {
+        let routes: Vec<Route> = ["/=http.request.default",
+                                  "/api=http.request.api",
+                                  "/api/admin=http.request.admin",
+                                  "*.example.com/api=http.request.tenant",
+                                  "static.example.com/=http.request.static"]
+            .iter()
+            .map(|route| route.parse().unwrap())
+            .collect();
+        let channel = |host, path| select(&routes, host, path).map(|route| &route.channel[..]);
+
+        assert_eq!(channel(None, "/"), Some("http.request.default"));
+        assert_eq!(channel(None, "/apiary"), Some("http.request.default"));
+        assert_eq!(channel(None, "/api"), Some("http.request.api"));
+        assert_eq!(channel(None, "/api/users"), Some("http.request.api"));
+        assert_eq!(channel(None, "/api/admin/users"), Some("http.request.admin"));
+        assert_eq!(channel(Some("foo.example.com"), "/api/admin"), Some("http.request.tenant"));
+        assert_eq!(channel(Some("static.example.com:8000"), "/api"),
+                   Some("http.request.static"));
+        assert_eq!(channel(Some("STATIC.example.com"), "/"), Some("http.request.static"));
+
+        assert_eq!(select(&routes[1..2], None, "/"), None);
+    }
+}
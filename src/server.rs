@@ -0,0 +1,503 @@
+use std::fs;
+use std::io;
+use std::net;
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures;
+use futures::{Future, Poll, Stream};
+use hyper::server::Http;
+use rand::{thread_rng, Rng};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::ServerConfigExt;
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
+use tokio_uds::UnixListener;
+
+use channels::ChannelLayer;
+use cidr;
+use cidr::Cidr;
+use http::{AsgiHttpServiceFactory, ConnectionInfo};
+use limits::{Counter, Limit, Permit};
+use proxy_protocol;
+use slow_clients::{within, Activity, ConnectionTimeouts, Watched};
+use tls::Tls;
+
+
+/// Somewhere for the server to accept connections from.
+#[derive(Clone, Debug)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    /// A Unix domain socket at the given path, optionally with its permissions set to the given
+    /// mode before anyone can connect to it. The socket is removed when the server stops.
+    Unix(PathBuf, Option<u32>),
+    /// A listening TCP or Unix domain socket that we've inherited from our parent, e.g. systemd.
+    Fd(RawFd),
+}
+
+
+/// A listener that the server should accept connections from, and whether we should terminate
+/// TLS on its connections.
+#[derive(Clone, Debug)]
+pub struct Listen {
+    pub bind: Bind,
+    pub tls: bool,
+}
+
+
+enum Listener {
+    Tcp(TcpListener),
+    // Sockets we created ourselves come with the file to remove when we're done with them.
+    Unix(UnixListener, Option<SocketFile>),
+}
+
+impl Listener {
+    fn bind(bind: &Bind, handle: &Handle) -> io::Result<Self> {
+        match *bind {
+            Bind::Tcp(ref addr) => Ok(Listener::Tcp(TcpListener::bind(addr, handle)?)),
+            Bind::Unix(ref path, mode) => {
+                let (listener, file) = bind_unix(path, mode, handle)?;
+                Ok(Listener::Unix(listener, Some(file)))
+            }
+            Bind::Fd(fd) => {
+                // Asking for a Unix domain socket's address fails if the socket is in another
+                // family, which tells us which kind of listener it is.
+                let listener = unsafe { StdUnixListener::from_raw_fd(fd) };
+                if listener.local_addr().is_ok() {
+                    let listener = UnixListener::from_listener(listener, handle)?;
+                    return Ok(Listener::Unix(listener, None));
+                }
+                let listener = unsafe { net::TcpListener::from_raw_fd(listener.into_raw_fd()) };
+                let addr = listener.local_addr()?;
+                Ok(Listener::Tcp(TcpListener::from_listener(listener, &addr, handle)?))
+            }
+        }
+    }
+}
+
+
+// Binds a Unix domain socket at path. The socket is created in a directory only we can get into
+// and renamed into place once its permissions are set, so that nobody can connect to it before
+// then. This replaces a socket left behind by a previous run, but not anything else.
+fn bind_unix(path: &Path,
+             mode: Option<u32>,
+             handle: &Handle)
+             -> io::Result<(UnixListener, SocketFile)> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} exists and is not a socket", path.display())));
+        }
+    }
+
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Unix socket path has no name"))?;
+    let suffix: String = thread_rng().gen_ascii_chars().take(10).collect();
+    let private_dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), suffix));
+    fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join(name);
+
+    let bound = UnixListener::bind(&private_path, handle).and_then(|listener| {
+        if let Some(mode) = mode {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(mode))?;
+        }
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    // Whether or not we managed to move the socket out, we're done with the directory.
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    let listener = bound?;
+
+    let metadata = fs::symlink_metadata(path)?;
+    let file = SocketFile {
+        path: path.to_owned(),
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    };
+    Ok((listener, file))
+}
+
+
+// The file for a Unix domain socket we've bound, which is removed when we stop listening on it.
+struct SocketFile {
+    path: PathBuf,
+    // Identifies our socket, so that we don't remove one another process has since put in its
+    // place.
+    dev: u64,
+    ino: u64,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            if metadata.dev() == self.dev && metadata.ino() == self.ino {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+
+// A connection which holds on to its slot until Hyper is done with it.
+struct Admitted<I> {
+    io: I,
+    _permit: Permit,
+}
+
+impl<I> io::Read for Admitted<I>
+    where I: io::Read
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<I> io::Write for Admitted<I>
+    where I: io::Write
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<I> AsyncRead for Admitted<I> where I: AsyncRead {}
+
+impl<I> AsyncWrite for Admitted<I>
+    where I: AsyncWrite
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+
+// Everything we need to start serving a newly accepted connection.
+struct Acceptor<C>
+    where C: ChannelLayer
+{
+    handle: Handle,
+    http: Http,
+    factory: AsgiHttpServiceFactory<C>,
+    tls: Option<Tls>,
+    // Connections from these addresses must begin with a PROXY protocol header.
+    proxy_protocol: Vec<Cidr>,
+    connections: Counter,
+    // How many connections may wait for a slot, and for how long, before we close them.
+    connection_queue_length: usize,
+    connection_queue_timeout: Duration,
+    timeouts: ConnectionTimeouts,
+}
+
+impl<C> Acceptor<C>
+    where C: ChannelLayer
+{
+    // Serves the connection once there's a slot free for it, closing it if one doesn't come free
+    // in time or too many others are already waiting.
+    fn admit<I>(acceptor: &Rc<Self>,
+                io: I,
+                tls_enabled: bool,
+                server: Option<SocketAddr>,
+                client: Option<SocketAddr>)
+        where I: 'static + AsyncRead + AsyncWrite
+    {
+        let client_ip = client.map(|addr| addr.ip());
+        if let Some(permit) = acceptor.connections.try_acquire(client_ip) {
+            let io = Admitted {
+                io: io,
+                _permit: permit,
+            };
+            acceptor.serve(io, tls_enabled, server, client);
+            return;
+        }
+
+        let admitted = acceptor.clone();
+        let connection = acceptor.connections
+            .acquire(client_ip,
+                     acceptor.connection_queue_length,
+                     acceptor.connection_queue_timeout,
+                     &acceptor.handle)
+            .map(move |permit| {
+                let io = Admitted {
+                    io: io,
+                    _permit: permit,
+                };
+                admitted.serve(io, tls_enabled, server, client);
+            })
+            .map_err(move |_| match client {
+                Some(addr) => println!("Closing connection from {}: too many connections", addr),
+                None => println!("Closing connection: too many connections"),
+            });
+        acceptor.handle.spawn(connection);
+    }
+
+    fn serve<I>(&self,
+                io: I,
+                tls_enabled: bool,
+                server: Option<SocketAddr>,
+                client: Option<SocketAddr>)
+        where I: 'static + AsyncRead + AsyncWrite
+    {
+        // Hyper insists on knowing the remote address of every connection, even though it's
+        // meaningless for Unix domain sockets. We always tell the application what the client's
+        // address is through ConnectionInfo, so it doesn't matter what we tell Hyper.
+        let remote_addr = client.unwrap_or_else(|| "0.0.0.0:0".parse().unwrap());
+        let activity = Activity::new();
+
+        match self.tls {
+            Some(ref tls) if tls_enabled => {
+                let service = self.factory.new_service(&self.handle, ConnectionInfo {
+                    scheme: "https",
+                    server: server,
+                    client: client,
+                    activity: Some(activity.clone()),
+                });
+                let http = self.http.clone();
+                let handle = self.handle.clone();
+                let timeouts = self.timeouts;
+                // We can only make sense of what the client sends once it's been decrypted. The
+                // handshake gets as long as the headers would to arrive.
+                let connection = within(tls.config().accept_async(io),
+                                        timeouts.header_timeout,
+                                        &self.handle)
+                    .map(move |stream| {
+                        let stream = Watched::new(stream, activity, timeouts, &handle);
+                        http.bind_connection(&handle, stream, remote_addr, service)
+                    })
+                    .map_err(move |err| {
+                        println!("TLS handshake with {} failed: {}", remote_addr, err)
+                    });
+                self.handle.spawn(connection);
+            }
+            _ => {
+                let service = self.factory.new_service(&self.handle, ConnectionInfo {
+                    scheme: "http",
+                    server: server,
+                    client: client,
+                    activity: Some(activity.clone()),
+                });
+                let io = Watched::new(io, activity, self.timeouts, &self.handle);
+                self.http.bind_connection(&self.handle, io, remote_addr, service);
+            }
+        }
+    }
+}
+
+
+/// Serves ASGI applications over HTTP, accepting connections on each of its listeners and
+/// serving them using services from the factory.
+pub struct Server<C>
+    where C: ChannelLayer
+{
+    factory: AsgiHttpServiceFactory<C>,
+    listens: Vec<Listen>,
+    tls: Option<Tls>,
+    proxy_protocol: Vec<Cidr>,
+    connection_limit: Limit,
+    connection_queue_length: usize,
+    connection_queue_timeout: Duration,
+    timeouts: ConnectionTimeouts,
+}
+
+impl<C> Server<C>
+    where C: ChannelLayer
+{
+    /// Creates a server with no listeners. Add some with `listen` before running it.
+    pub fn new(factory: AsgiHttpServiceFactory<C>) -> Self {
+        Server {
+            factory: factory,
+            listens: Vec::new(),
+            tls: None,
+            proxy_protocol: Vec::new(),
+            connection_limit: Limit::default(),
+            connection_queue_length: 0,
+            connection_queue_timeout: Duration::from_secs(0),
+            timeouts: ConnectionTimeouts::default(),
+        }
+    }
+
+    /// Adds somewhere to accept connections from.
+    pub fn listen(mut self, listen: Listen) -> Self {
+        self.listens.push(listen);
+        self
+    }
+
+    /// Sets the certificates to use on listeners with TLS enabled.
+    pub fn tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Expects TCP connections from these addresses to begin with a PROXY protocol header.
+    pub fn proxy_protocol(mut self, trusted: Vec<Cidr>) -> Self {
+        self.proxy_protocol = trusted;
+        self
+    }
+
+    /// Limits how many connections we serve at once. Up to `queue_length` connections beyond the
+    /// limit wait up to `queue_timeout` for another to close, and are closed if none does. Any
+    /// more are closed straight away.
+    pub fn connection_limit(mut self,
+                            limit: Limit,
+                            queue_length: usize,
+                            queue_timeout: Duration)
+                            -> Self {
+        self.connection_limit = limit;
+        self.connection_queue_length = queue_length;
+        self.connection_queue_timeout = queue_timeout;
+        self
+    }
+
+    /// Closes connections which are idle or slow to send their headers for too long.
+    pub fn timeouts(mut self, timeouts: ConnectionTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Accepts connections until the process receives SIGINT or SIGTERM. The factory's ReplyPump
+    /// and channel pool are shared by all of them.
+    pub fn run(self) -> io::Result<()> {
+        run(&self.listens,
+            self.factory,
+            self.tls,
+            self.proxy_protocol,
+            self.connection_limit,
+            self.connection_queue_length,
+            self.connection_queue_timeout,
+            self.timeouts)
+    }
+}
+
+
+fn run<C>(listens: &[Listen],
+          factory: AsgiHttpServiceFactory<C>,
+          tls: Option<Tls>,
+          proxy_protocol: Vec<Cidr>,
+          connection_limit: Limit,
+          connection_queue_length: usize,
+          connection_queue_timeout: Duration,
+          timeouts: ConnectionTimeouts)
+          -> io::Result<()>
+    where C: ChannelLayer
+{
+    let mut core = Core::new()?;
+    let handle = core.handle();
+
+    // Re-read our certificates whenever we receive SIGHUP, so that they can be renewed without
+    // restarting the server.
+    if let Some(ref tls) = tls {
+        let tls = tls.clone();
+        let reloads = Signal::new(SIGHUP, &handle)
+            .flatten_stream()
+            .for_each(move |_| {
+                match tls.reload() {
+                    Ok(()) => println!("Reloaded TLS certificates"),
+                    Err(err) => println!("Failed to reload TLS certificates: {}", err),
+                }
+                Ok(())
+            })
+            .map_err(|err| println!("Stopped listening for SIGHUP: {}", err));
+        handle.spawn(reloads);
+    }
+
+    let server = Rc::new(Acceptor {
+        handle: handle.clone(),
+        http: Http::new(),
+        factory: factory,
+        tls: tls,
+        proxy_protocol: proxy_protocol,
+        connections: Counter::new(connection_limit),
+        connection_queue_length: connection_queue_length,
+        connection_queue_timeout: connection_queue_timeout,
+        timeouts: timeouts,
+    });
+
+    let mut listeners: Vec<Box<Future<Item = (), Error = io::Error>>> = Vec::new();
+    // Removed from the filesystem once we return, however we come to.
+    let mut socket_files = Vec::new();
+    for listen in listens {
+        let server = server.clone();
+        let tls_enabled = listen.tls;
+        if tls_enabled && server.tls.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "TLS listener configured without any certificates"));
+        }
+
+        match Listener::bind(&listen.bind, &handle)? {
+            Listener::Tcp(listener) => {
+                listeners.push(Box::new(listener.incoming().for_each(move |(socket, remote_addr)| {
+                    let local_addr = socket.local_addr().ok();
+                    if !cidr::contains_any(&server.proxy_protocol, &remote_addr.ip()) {
+                        let client = Some(remote_addr);
+                        Acceptor::admit(&server, socket, tls_enabled, local_addr, client);
+                        return Ok(());
+                    }
+
+                    // This connection is from a trusted proxy, which will tell us the client's
+                    // real address before anything else. Until it does, the connection takes a
+                    // slot under the proxy's address, so that connections waiting on a header
+                    // can't get around the limits.
+                    let proxied_server = server.clone();
+                    let handle = server.handle.clone();
+                    let timeout = server.timeouts
+                        .header_timeout
+                        .unwrap_or(Duration::from_secs(proxy_protocol::DEFAULT_TIMEOUT_SECS));
+                    let connection = server.connections
+                        .acquire(Some(remote_addr.ip()),
+                                 server.connection_queue_length,
+                                 server.connection_queue_timeout,
+                                 &server.handle)
+                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "too many connections"))
+                        .and_then(move |permit| {
+                            within(proxy_protocol::read_header(socket), Some(timeout), &handle)
+                                .map(move |header| (permit, header))
+                        })
+                        .map(move |(permit, (header, stream))| {
+                            let local_addr = header.destination.or(local_addr);
+                            let remote_addr = header.source.unwrap_or(remote_addr);
+                            // Give up the proxy's slot for one under the client's address.
+                            drop(permit);
+                            Acceptor::admit(&proxied_server,
+                                            stream,
+                                            tls_enabled,
+                                            local_addr,
+                                            Some(remote_addr));
+                        })
+                        .map_err(move |err| {
+                            println!("Closing connection from {}: {}", remote_addr, err)
+                        });
+                    server.handle.spawn(connection);
+                    Ok(())
+                })));
+            }
+            Listener::Unix(listener, file) => {
+                socket_files.extend(file);
+                listeners.push(Box::new(listener.incoming().for_each(move |(socket, _)| {
+                    Acceptor::admit(&server, socket, tls_enabled, None, None);
+                    Ok(())
+                })));
+            }
+        }
+    }
+
+    // Stop when asked to, rather than being killed, so that we get to clean up our sockets.
+    let shutdown = Signal::new(SIGINT, &handle)
+        .flatten_stream()
+        .select(Signal::new(SIGTERM, &handle).flatten_stream())
+        .into_future()
+        .map(|(signal, _)| println!("Shutting down on signal {}", signal.unwrap_or(0)))
+        .map_err(|(err, _)| err);
+
+    let listeners = futures::future::join_all(listeners).map(|_| ());
+    core.run(listeners.select(shutdown).map(|_| ()).map_err(|(err, _)| err))
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use r2d2;
+
+use channels::ChannelLayer;
+use routing;
+
+
+// How often we check the length of the queues we're watching.
+const POLL_INTERVAL_MS: u64 = 100;
+
+
+/// When to refuse requests because the application's workers have fallen behind.
+#[derive(Clone, Debug)]
+pub struct LoadSheddingOptions {
+    /// Refuse requests for channels with more than this many messages waiting on them.
+    pub max_queue_length: usize,
+    /// Never refuse requests for paths under one of these, e.g. health checks. They only match
+    /// whole path segments.
+    pub exempt_paths: Vec<String>,
+}
+
+impl LoadSheddingOptions {
+    pub fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths.iter().any(|prefix| routing::has_path_prefix(path, prefix))
+    }
+}
+
+
+/// Keeps track of how many requests are waiting on each of a set of channels, checking them from
+/// a background thread so that requests never wait on it. The thread stops once the monitor and
+/// all of its clones have been dropped.
+#[derive(Clone)]
+pub struct QueueMonitor {
+    lengths: Arc<HashMap<String, AtomicUsize>>,
+}
+
+impl QueueMonitor {
+    pub fn start<C>(channel_pool: r2d2::Pool<C::Manager>, channels: Vec<String>) -> Self
+        where C: ChannelLayer
+    {
+        let lengths: HashMap<String, AtomicUsize> =
+            channels.into_iter().map(|channel| (channel, AtomicUsize::new(0))).collect();
+        let monitor = QueueMonitor { lengths: Arc::new(lengths) };
+
+        // The thread only holds on to the lengths while it's checking them, and stops once every
+        // clone of the monitor has been dropped.
+        let lengths = Arc::downgrade(&monitor.lengths);
+        thread::spawn(move || while let Some(lengths) = lengths.upgrade() {
+            match channel_pool.get() {
+                Ok(channel_layer) => {
+                    for (channel, length) in lengths.iter() {
+                        match channel_layer.queue_length(channel) {
+                            Ok(queued) => length.store(queued, Ordering::Relaxed),
+                            // Keep the last length we saw - we'd rather not start refusing or
+                            // accepting everything because of a blip.
+                            Err(err) => println!("Failed to check length of {}: {}", channel, err),
+                        }
+                    }
+                }
+                Err(err) => println!("Failed to get channel layer to check queues: {}", err),
+            }
+            drop(lengths);
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        });
+
+        monitor
+    }
+
+    /// The number of messages waiting on the channel when we last checked. Channels we aren't
+    /// watching are assumed to be empty.
+    pub fn queue_length(&self, channel: &str) -> usize {
+        self.lengths.get(channel).map_or(0, |length| length.load(Ordering::Relaxed))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::LoadSheddingOptions;
+
+    #[test]
+    fn test_is_exempt() {
+        let options = LoadSheddingOptions {
+            max_queue_length: 100,
+            exempt_paths: vec!["/health".to_owned(), "/admin/".to_owned()],
+        };
+        assert!(options.is_exempt("/health"));
+        assert!(options.is_exempt("/health/db"));
+        assert!(options.is_exempt("/admin"));
+        assert!(options.is_exempt("/admin/login"));
+        assert!(!options.is_exempt("/"));
+        assert!(!options.is_exempt("/healthz"));
+        assert!(!options.is_exempt("/administrator"));
+    }
+}
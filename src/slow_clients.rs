@@ -0,0 +1,578 @@
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures;
+use futures::{Async, Future, Poll, Stream};
+use hyper::status::StatusCode;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+
+// What we send to clients whose headers we give up on, before closing their connection. Hyper
+// isn't involved until the headers are complete, so we have to write these ourselves.
+const REQUEST_TIMEOUT: &'static [u8] = b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\n\
+                                         Content-Length: 0\r\n\r\n";
+const HEADERS_TOO_LARGE: &'static [u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\
+                                           Connection: close\r\nContent-Length: 0\r\n\r\n";
+
+// Uploads get this long to get up to speed before we hold them to a minimum rate.
+const UPLOAD_GRACE_SECS: u64 = 5;
+
+
+/// Limits on how long connections may spend sending request headers or sitting idle, so that
+/// slow clients can't tie them up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionTimeouts {
+    /// Reply 408 and close the connection if a request's headers take longer than this to arrive,
+    /// counting from when the connection was accepted or went idle. Also limits how long TLS
+    /// handshakes and PROXY protocol headers may take.
+    pub header_timeout: Option<Duration>,
+    /// Close connections which have been idle between requests for this long.
+    pub keep_alive_timeout: Option<Duration>,
+    /// Reply 431 and close the connection if a request's headers are larger than this many bytes.
+    pub max_header_size: Option<usize>,
+}
+
+
+#[derive(Debug)]
+enum Phase {
+    // Waiting for the next request, since the given time.
+    Idle(Instant),
+    // Reading a request's headers. We count how many bytes they've taken, and how far we are
+    // through the blank line which ends them.
+    Headers {
+        started: Instant,
+        size: usize,
+        matched: usize,
+    },
+    // Reading a request's body or writing its response, which may take as long as they take.
+    Busy,
+    // Done with the request, but Hyper may not have finished writing out its response. We count
+    // as idle once it has been flushed.
+    Finishing,
+}
+
+#[derive(Debug)]
+struct ActivityState {
+    phase: Phase,
+    // Requests the service has started but not finished responding to.
+    requests: usize,
+}
+
+
+/// What a connection is up to, shared between the connection and the service handling its
+/// requests.
+#[derive(Clone, Debug)]
+pub struct Activity(Arc<Mutex<ActivityState>>);
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity(Arc::new(Mutex::new(ActivityState {
+            phase: Phase::Idle(Instant::now()),
+            requests: 0,
+        })))
+    }
+
+    /// Marks the connection busy until the guard is dropped, once the response is complete.
+    pub fn begin_request(&self) -> RequestGuard {
+        let mut state = self.0.lock().unwrap();
+        state.requests += 1;
+        state.phase = Phase::Busy;
+        RequestGuard(self.clone())
+    }
+}
+
+
+/// A request a connection is handling. The connection counts as idle once it has none, and
+/// their responses have been flushed.
+#[derive(Debug)]
+pub struct RequestGuard(Activity);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let mut state = (self.0).0.lock().unwrap();
+        state.requests -= 1;
+        if state.requests == 0 {
+            state.phase = Phase::Finishing;
+        }
+    }
+}
+
+
+/// A connection which is closed if it spends too long idle or sending headers.
+pub struct Watched<I> {
+    io: I,
+    activity: Activity,
+    timeouts: ConnectionTimeouts,
+    handle: Handle,
+    // Wakes us up to check on the connection if nothing else does.
+    timer: Option<(Instant, Timeout)>,
+    closed: bool,
+}
+
+impl<I> Watched<I>
+    where I: AsyncRead + AsyncWrite
+{
+    pub fn new(io: I, activity: Activity, timeouts: ConnectionTimeouts, handle: &Handle) -> Self {
+        Watched {
+            io: io,
+            activity: activity,
+            timeouts: timeouts,
+            handle: handle.clone(),
+            timer: None,
+            closed: false,
+        }
+    }
+
+    // When we'll give up on the connection if nothing happens, if ever. Idle connections wait for
+    // their next request's headers for as long as keep-alive allows, or failing that, the header
+    // timeout.
+    fn deadline(&self) -> Option<Instant> {
+        match self.activity.0.lock().unwrap().phase {
+            Phase::Idle(since) => {
+                let timeout = self.timeouts.keep_alive_timeout.or(self.timeouts.header_timeout);
+                timeout.map(|timeout| since + timeout)
+            }
+            Phase::Headers { started, .. } => {
+                self.timeouts.header_timeout.map(|timeout| started + timeout)
+            }
+            Phase::Busy | Phase::Finishing => None,
+        }
+    }
+
+    // Tells the client why, if they're owed an explanation, then reports end-of-file so that Hyper
+    // closes the connection.
+    fn close(&mut self, response: Option<&[u8]>) -> io::Result<usize> {
+        if let Some(response) = response {
+            // This is our last word, so we don't mind if it doesn't all fit in the socket buffer.
+            let _ = self.io.write(response);
+        }
+        self.closed = true;
+        Ok(0)
+    }
+
+    // Keeps track of the headers of the request being read, returning the response to close the
+    // connection with if they're too large.
+    fn received(&self, buf: &[u8]) -> Option<&'static [u8]> {
+        let mut state = self.activity.0.lock().unwrap();
+        // Without a keep-alive timeout, the header timeout has been running since the connection
+        // went idle. A request pipelined behind a response that's still being written starts now.
+        let started = match (&state.phase, self.timeouts.keep_alive_timeout) {
+            (&Phase::Idle(since), None) => Some(since),
+            (&Phase::Idle(_), Some(_)) |
+            (&Phase::Finishing, _) => Some(Instant::now()),
+            _ => None,
+        };
+        if let Some(started) = started {
+            state.phase = Phase::Headers {
+                started: started,
+                size: 0,
+                matched: 0,
+            };
+        }
+        // Like Hyper, we take lines to end with LF, with or without a CR before it. matched is 1
+        // at the start of a line, and 2 if that line has begun with a CR. Another LF then ends
+        // the headers.
+        let mut complete = false;
+        if let Phase::Headers { ref mut size, ref mut matched, .. } = state.phase {
+            for &byte in buf {
+                *size += 1;
+                *matched = match (byte, *matched) {
+                    (b'\n', 1) | (b'\n', 2) => 3,
+                    (b'\n', _) => 1,
+                    (b'\r', 1) => 2,
+                    _ => 0,
+                };
+                if *matched == 3 {
+                    complete = true;
+                    break;
+                }
+            }
+            if self.timeouts.max_header_size.map_or(false, |max| *size > max) {
+                return Some(HEADERS_TOO_LARGE);
+            }
+        }
+        if complete {
+            state.phase = Phase::Busy;
+        }
+        None
+    }
+
+    // Arranges to be woken at the deadline, returning whether it has already passed.
+    fn wait_until(&mut self, deadline: Instant) -> io::Result<bool> {
+        if self.timer.as_ref().map_or(true, |&(at, _)| at != deadline) {
+            self.timer = Some((deadline, Timeout::new_at(deadline, &self.handle)?));
+        }
+        match self.timer {
+            Some((_, ref mut timer)) => Ok(timer.poll()?.is_ready()),
+            None => Ok(false),
+        }
+    }
+}
+
+impl<I> io::Read for Watched<I>
+    where I: AsyncRead + AsyncWrite
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.closed {
+            return Ok(0);
+        }
+        match self.io.read(buf) {
+            Ok(0) => Ok(0),
+            Ok(read) => {
+                match self.received(&buf[..read]) {
+                    Some(response) => self.close(Some(response)),
+                    None => Ok(read),
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let expired = match self.deadline() {
+                    Some(deadline) => self.wait_until(deadline)?,
+                    None => false,
+                };
+                let phase_is_headers = match self.activity.0.lock().unwrap().phase {
+                    Phase::Headers { .. } => true,
+                    _ => false,
+                };
+                match (expired, phase_is_headers) {
+                    (true, true) => self.close(Some(REQUEST_TIMEOUT)),
+                    (true, false) => self.close(None),
+                    (false, _) => Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<I> io::Write for Watched<I>
+    where I: AsyncRead + AsyncWrite
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Hyper may still be writing out a response after the request is complete, and the
+        // connection isn't idle until it's done.
+        if let Phase::Idle(ref mut since) = self.activity.0.lock().unwrap().phase {
+            *since = Instant::now();
+        }
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()?;
+        // Everything Hyper has written has now gone out, so if the last response was complete,
+        // we're waiting for the next request.
+        let mut state = self.activity.0.lock().unwrap();
+        if let Phase::Finishing = state.phase {
+            state.phase = Phase::Idle(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+impl<I> AsyncRead for Watched<I> where I: AsyncRead + AsyncWrite {}
+
+impl<I> AsyncWrite for Watched<I>
+    where I: AsyncRead + AsyncWrite
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+
+/// Fails the future with a TimedOut error if it takes longer than the timeout, if there is one.
+pub fn within<F>(future: F,
+                 timeout: Option<Duration>,
+                 handle: &Handle)
+                 -> Box<Future<Item = F::Item, Error = io::Error>>
+    where F: 'static + Future<Error = io::Error>
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Box::new(future),
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(err) => return Box::new(futures::future::err(err)),
+    };
+    let timed_out = timer.then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out")));
+    Box::new(future.select(timed_out).map(|(item, _)| item).map_err(|(err, _)| err))
+}
+
+
+/// Fails a request body with a 408 if it stops arriving for longer than `idle_timeout`, or, once
+/// it has had a few seconds to get going, arrives at fewer than `min_rate` bytes per second.
+pub struct TimedBody<S> {
+    body: S,
+    idle_timeout: Option<Duration>,
+    min_rate: Option<usize>,
+    handle: Handle,
+    started: Instant,
+    last_chunk: Instant,
+    received: usize,
+    timer: Option<(Instant, Timeout)>,
+}
+
+impl<S> TimedBody<S> {
+    pub fn new(body: S,
+               idle_timeout: Option<Duration>,
+               min_rate: Option<usize>,
+               handle: &Handle)
+               -> Self {
+        let now = Instant::now();
+        TimedBody {
+            body: body,
+            idle_timeout: idle_timeout,
+            // A minimum of nothing is no minimum at all.
+            min_rate: min_rate.and_then(|rate| match rate {
+                0 => None,
+                rate => Some(rate),
+            }),
+            handle: handle.clone(),
+            started: now,
+            last_chunk: now,
+            received: 0,
+            timer: None,
+        }
+    }
+
+    // When the body will have been too slow, unless more of it arrives first.
+    fn deadline(&self) -> Option<Instant> {
+        let idle = self.idle_timeout.map(|timeout| self.last_chunk + timeout);
+        let slow = self.min_rate.map(|rate| {
+            let expected = Duration::from_millis((self.received as u64 * 1000) / rate as u64);
+            self.started + expected.max(Duration::from_secs(UPLOAD_GRACE_SECS))
+        });
+        match (idle, slow) {
+            (Some(idle), Some(slow)) => Some(idle.min(slow)),
+            (idle, slow) => idle.or(slow),
+        }
+    }
+}
+
+impl<S> Stream for TimedBody<S>
+    where S: Stream,
+          S::Item: AsRef<[u8]>
+{
+    type Item = S::Item;
+    type Error = (StatusCode, &'static str);
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Self::Error> {
+        let timed_out = (StatusCode::RequestTimeout, "Request body took too long");
+        match self.body.poll() {
+            Ok(Async::Ready(Some(chunk))) => {
+                self.last_chunk = Instant::now();
+                self.received += chunk.as_ref().len();
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => {
+                let deadline = match self.deadline() {
+                    Some(deadline) => deadline,
+                    None => return Ok(Async::NotReady),
+                };
+                if self.timer.as_ref().map_or(true, |&(at, _)| at != deadline) {
+                    let timer = Timeout::new_at(deadline, &self.handle).map_err(|_| timed_out)?;
+                    self.timer = Some((deadline, timer));
+                }
+                let expired = match self.timer {
+                    Some((_, ref mut timer)) => timer.poll().map_err(|_| timed_out)?.is_ready(),
+                    None => false,
+                };
+                match expired {
+                    true => Err(timed_out),
+                    false => Ok(Async::NotReady),
+                }
+            }
+            Err(_) => Err((StatusCode::InternalServerError, "Unknown server error")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Activity, ConnectionTimeouts, HEADERS_TOO_LARGE, Phase, REQUEST_TIMEOUT,
+                TimedBody, UPLOAD_GRACE_SECS, Watched};
+
+    use std::cell::RefCell;
+    use std::io;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use futures::{Async, Poll, Stream};
+    use futures::future::poll_fn;
+    use futures::sync::mpsc;
+    use hyper::status::StatusCode;
+    use tokio_core::reactor::Core;
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    // A connection which has sent what it's going to send, and keeps what we write to it.
+    struct MockIo {
+        input: Vec<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for MockIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+            let len = buf.len().min(self.input.len());
+            buf[..len].copy_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockIo {}
+
+    impl AsyncWrite for MockIo {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    // Reads from a watched connection until it's closed, returning what it wrote back.
+    fn run_watched(input: &[u8], timeouts: ConnectionTimeouts) -> Vec<u8> {
+        let mut core = Core::new().unwrap();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let io = MockIo {
+            input: input.to_vec(),
+            output: output.clone(),
+        };
+        let mut watched = Watched::new(io, Activity::new(), timeouts, &core.handle());
+        core.run(poll_fn(move || -> Poll<(), io::Error> {
+                let mut buf = [0; 1024];
+                loop {
+                    match watched.read(&mut buf) {
+                        Ok(0) => return Ok(Async::Ready(())),
+                        Ok(_) => {}
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(Async::NotReady)
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+            }))
+            .unwrap();
+        let output = output.borrow().clone();
+        output
+    }
+
+    #[test]
+    fn test_activity() {
+        let activity = Activity::new();
+        let first = activity.begin_request();
+        let second = activity.begin_request();
+        drop(first);
+        match activity.0.lock().unwrap().phase {
+            Phase::Busy => {}
+            ref phase => panic!("Expected Busy, got {:?}", phase),
+        }
+        drop(second);
+        match activity.0.lock().unwrap().phase {
+            Phase::Finishing => {}
+            ref phase => panic!("Expected Finishing, got {:?}", phase),
+        }
+    }
+
+    #[test]
+    fn test_watched() {
+        let timeout = Some(Duration::from_millis(10));
+
+        // Headers which never finish get a 408.
+        let timeouts = ConnectionTimeouts { header_timeout: timeout, ..Default::default() };
+        assert_eq!(run_watched(b"GET / HTTP/1.1\r\nHost: exa", timeouts), REQUEST_TIMEOUT);
+
+        // As do connections which never send anything.
+        assert_eq!(run_watched(b"", timeouts), b"");
+
+        // Headers which are too large get a 431 straight away.
+        let timeouts = ConnectionTimeouts { max_header_size: Some(16), ..Default::default() };
+        assert_eq!(run_watched(b"GET / HTTP/1.1\r\nHost: example.com\r\n", timeouts),
+                   HEADERS_TOO_LARGE);
+
+        // Idle connections are closed without a word.
+        let timeouts = ConnectionTimeouts { keep_alive_timeout: timeout, ..Default::default() };
+        assert_eq!(run_watched(b"", timeouts), b"");
+    }
+
+    #[test]
+    fn test_watched_end_of_headers() {
+        let core = Core::new().unwrap();
+        let ends = [&b"\r\n\r\n"[..], b"\n\n", b"\n\r\n", b"\r\n\n"];
+        for end in ends.iter() {
+            let io = MockIo {
+                input: Vec::new(),
+                output: Rc::new(RefCell::new(Vec::new())),
+            };
+            let watched = Watched::new(io, Activity::new(), Default::default(), &core.handle());
+            let mut request = b"GET / HTTP/1.1\nHost: example.com".to_vec();
+            assert_eq!(watched.received(&request), None);
+            request.clear();
+            request.extend_from_slice(end);
+            assert_eq!(watched.received(&request), None);
+            match watched.activity.0.lock().unwrap().phase {
+                Phase::Busy => {}
+                ref phase => panic!("Expected Busy after {:?}, got {:?}", end, phase),
+            }
+        }
+
+        // A CR on its own doesn't end a line.
+        let io = MockIo {
+            input: Vec::new(),
+            output: Rc::new(RefCell::new(Vec::new())),
+        };
+        let watched = Watched::new(io, Activity::new(), Default::default(), &core.handle());
+        assert_eq!(watched.received(b"GET / HTTP/1.1\r\r\nHost: a\r\r\n"), None);
+        match watched.activity.0.lock().unwrap().phase {
+            Phase::Headers { .. } => {}
+            ref phase => panic!("Expected Headers, got {:?}", phase),
+        }
+    }
+
+    #[test]
+    fn test_timed_body_idle() {
+        let mut core = Core::new().unwrap();
+        let (_sender, receiver) = mpsc::channel::<Vec<u8>>(1);
+        let body = TimedBody::new(receiver, Some(Duration::from_millis(10)), None, &core.handle());
+        match core.run(body.collect()) {
+            Err((status, _)) => assert_eq!(status, StatusCode::RequestTimeout),
+            Ok(_) => panic!("Expected the body to time out"),
+        }
+    }
+
+    #[test]
+    fn test_timed_body_min_rate() {
+        let mut core = Core::new().unwrap();
+        let (_sender, receiver) = mpsc::channel::<Vec<u8>>(1);
+        let mut body = TimedBody::new(receiver, None, Some(1000), &core.handle());
+
+        // Slow starts are allowed for, then the body must keep up.
+        let grace = Duration::from_secs(UPLOAD_GRACE_SECS);
+        assert_eq!(body.deadline(), Some(body.started + grace));
+        body.received = 20000;
+        assert_eq!(body.deadline(), Some(body.started + Duration::from_secs(20)));
+
+        body.started -= Duration::from_secs(30);
+        match core.run(body.collect()) {
+            Err((status, _)) => assert_eq!(status, StatusCode::RequestTimeout),
+            Ok(_) => panic!("Expected the body to be too slow"),
+        }
+    }
+}
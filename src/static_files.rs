@@ -0,0 +1,426 @@
+use std::fs::File;
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use hyper;
+use hyper::{Headers, Method};
+use hyper::header::{ByteRangeSpec, ContentLength, ContentType, EntityTag, ETag, HttpDate,
+                    IfModifiedSince, IfRange, LastModified, Range};
+use hyper::server::Response;
+use hyper::status::StatusCode;
+use mime_guess::guess_mime_type;
+
+use body::BodyStream;
+use channels::ChannelLayer;
+use compression::accepts_encoding;
+use slow_clients::RequestGuard;
+
+
+/// A directory whose files we serve ourselves, for requests with paths under a prefix.
+#[derive(Clone, Debug)]
+pub struct StaticMount {
+    prefix: String,
+    root: PathBuf,
+}
+
+impl StaticMount {
+    /// Parses a `--static` value of the form `PREFIX=DIR`, e.g. `/static/=/srv/app/static`.
+    pub fn parse(mount: &str) -> io::Result<Self> {
+        let index = match mount.find('=') {
+            Some(index) => index,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Static mounts must be of the form PREFIX=DIR"))
+            }
+        };
+        Ok(StaticMount {
+            prefix: mount[..index].trim_right_matches('/').to_owned(),
+            // Resolve symlinks in the root up front, so that we can check files don't escape it.
+            root: Path::new(&mount[index + 1..]).canonicalize()?,
+        })
+    }
+
+    // The rest of the request path after our prefix, or None if the path isn't under it.
+    fn matching<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if !path.starts_with(&self.prefix) {
+            return None;
+        }
+        let rest = &path[self.prefix.len()..];
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        Some(rest)
+    }
+}
+
+fn resolve_under(root: &Path, rest: &str) -> Option<PathBuf> {
+    let mut file_path = root.to_path_buf();
+    for segment in rest.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ if segment.contains('\\') || segment.contains('\0') => return None,
+            _ => file_path.push(segment),
+        }
+    }
+
+    match is_contained(root, &file_path) {
+        true => Some(file_path),
+        false => None,
+    }
+}
+
+// Whether the path exists and is under the root, so that we don't follow symlinks out of it.
+fn is_contained(root: &Path, path: &Path) -> bool {
+    match path.canonicalize() {
+        Ok(ref canonical) => canonical.starts_with(root),
+        Err(_) => false,
+    }
+}
+
+
+// The precompressed copies of a file we look for, as (encoding, extension), in order of
+// preference.
+const PRECOMPRESSED: &'static [(&'static str, &'static str)] = &[("br", "br"), ("gzip", "gz")];
+
+
+/// Serves the request from disk if its path is under one of the static mounts. Returns None if
+/// the request should be passed on to the application. Looking for the file may block, so we do
+/// it on the thread-pool. A file's body takes the request guard, to hold on to until it has been
+/// sent.
+pub fn serve<C>(mounts: &[StaticMount],
+                method: &Method,
+                path: &str,
+                headers: &Headers,
+                cpu_pool: &CpuPool,
+                request: &mut Option<RequestGuard>)
+                -> Option<Box<Future<Item = Response<BodyStream<C>>, Error = hyper::Error>>>
+    where C: ChannelLayer
+{
+    let (mount, rest) = match mounts.iter()
+        .filter_map(|mount| mount.matching(path).map(|rest| (mount, rest)))
+        .next() {
+        Some(matched) => matched,
+        None => return None,
+    };
+
+    match *method {
+        Method::Get | Method::Head => {}
+        _ => {
+            let mut response = empty_response(StatusCode::MethodNotAllowed);
+            response.headers_mut().set_raw("Allow", "GET, HEAD");
+            return Some(Box::new(futures::future::ok(response)));
+        }
+    }
+
+    // Prefer a precompressed copy of the file, if the client will accept it and we have one. We
+    // try them in the order the client prefers them, or ours if it has no preference.
+    let mut encodings: Vec<(f32, (&'static str, &'static str))> = PRECOMPRESSED.iter()
+        .filter_map(|&(name, extension)| {
+            accepts_encoding(headers, name).map(|q| (q, (name, extension)))
+        })
+        .collect();
+    encodings.sort_by(|&(a, _), &(b, _)| b.partial_cmp(&a).unwrap());
+    let encodings: Vec<(&'static str, &'static str)> =
+        encodings.into_iter().map(|(_, encoding)| encoding).collect();
+    let root = mount.root.clone();
+    let rest = rest.to_owned();
+    let head = *method == Method::Head;
+    let headers = headers.clone();
+    let body_pool = cpu_pool.clone();
+    let request = request.take();
+
+    let response = cpu_pool.spawn_fn(move || open_file(&root, &rest, &encodings))
+        .then(move |opened| {
+            let response = opened.and_then(|opened| {
+                    file_response(opened, head, &headers, body_pool, request)
+                })
+                .unwrap_or_else(|_| empty_response(StatusCode::NotFound));
+            Ok::<_, hyper::Error>(response)
+        });
+    Some(Box::new(response))
+}
+
+
+// A file we've found to serve, along with what we know about it.
+struct OpenFile {
+    // The path of the file requested, rather than of any precompressed copy.
+    path: PathBuf,
+    file: File,
+    encoding: Option<&'static str>,
+    len: u64,
+    modified: SystemTime,
+}
+
+// Finds and opens the file for the rest of a request's path under the root, or a precompressed
+// copy of it in one of the encodings. These all touch the disk, so may block.
+fn open_file(root: &Path,
+             rest: &str,
+             encodings: &[(&'static str, &'static str)])
+             -> io::Result<OpenFile> {
+    let path = match resolve_under(root, rest) {
+        Some(path) => path,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "Not under the root")),
+    };
+
+    let mut encoding = None;
+    let mut file = None;
+    for &(name, extension) in encodings {
+        let mut compressed_path = path.as_os_str().to_owned();
+        compressed_path.push(".");
+        compressed_path.push(extension);
+        // The copy may be a symlink too, so it needs the same check as the file itself.
+        if !is_contained(root, Path::new(&compressed_path)) {
+            continue;
+        }
+        if let Ok(compressed) = File::open(&compressed_path) {
+            if compressed.metadata()?.is_file() {
+                encoding = Some(name);
+                file = Some(compressed);
+                break;
+            }
+        }
+    }
+    let file = match file {
+        Some(file) => file,
+        None => File::open(&path)?,
+    };
+
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Not a file"));
+    }
+    Ok(OpenFile {
+        path: path,
+        file: file,
+        encoding: encoding,
+        len: metadata.len(),
+        modified: metadata.modified()?,
+    })
+}
+
+
+fn file_response<C>(opened: OpenFile,
+                    head: bool,
+                    headers: &Headers,
+                    cpu_pool: CpuPool,
+                    request: Option<RequestGuard>)
+                    -> io::Result<Response<BodyStream<C>>>
+    where C: ChannelLayer
+{
+    let OpenFile { path, mut file, encoding, len, modified } = opened;
+    let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let etag = EntityTag::strong(format!("{:x}-{:x}{}",
+                                         modified_secs,
+                                         len,
+                                         encoding.map(|e| format!("-{}", e))
+                                             .unwrap_or_default()));
+
+    let mut response = Response::new();
+    response.headers_mut().set(ETag(etag.clone()));
+    response.headers_mut().set(LastModified(HttpDate::from(modified)));
+    response.headers_mut().set(ContentType(guess_mime_type(&path)));
+    response.headers_mut().set_raw("Accept-Ranges", "bytes");
+    response.headers_mut().set_raw("Vary", "Accept-Encoding");
+    if let Some(encoding) = encoding {
+        response.headers_mut().set_raw("Content-Encoding", encoding);
+    }
+
+    if !is_modified(headers, &etag, modified_secs) {
+        response.set_status(StatusCode::NotModified);
+        return Ok(response.with_body(BodyStream::empty()));
+    }
+
+    // Only send part of the file if it's part of the version the client already has.
+    let range = match if_range_matches(headers, &etag, modified_secs) {
+        true => requested_range(headers, len),
+        false => Ok(None),
+    };
+    let (start, end) = match range {
+        Ok(Some((start, end))) => {
+            response.set_status(StatusCode::PartialContent);
+            response.headers_mut()
+                .set_raw("Content-Range", format!("bytes {}-{}/{}", start, end - 1, len));
+            (start, end)
+        }
+        Ok(None) => (0, len),
+        Err(()) => {
+            response.set_status(StatusCode::RangeNotSatisfiable);
+            response.headers_mut().set_raw("Content-Range", format!("bytes */{}", len));
+            response.headers_mut().set(ContentLength(0));
+            return Ok(response.with_body(BodyStream::empty()));
+        }
+    };
+
+    response.headers_mut().set(ContentLength(end - start));
+    if head {
+        return Ok(response.with_body(BodyStream::empty()));
+    }
+    file.seek(SeekFrom::Start(start))?;
+    Ok(response.with_body(BodyStream::file(file, end - start, cpu_pool, request)))
+}
+
+
+// Whether we need to send the file, or whether the client's cached copy is still good. As per
+// RFC 7232, If-None-Match takes precedence over If-Modified-Since.
+fn is_modified(headers: &Headers, etag: &EntityTag, modified_secs: u64) -> bool {
+    if let Some(raw) = headers.get_raw("If-None-Match") {
+        for line in raw.iter() {
+            for tag in String::from_utf8_lossy(line).split(',').map(str::trim) {
+                let matches = tag == "*" ||
+                              tag.parse::<EntityTag>()
+                    .map(|tag| tag.weak_eq(etag))
+                    .unwrap_or(false);
+                if matches {
+                    return false;
+                }
+            }
+        }
+        return true;
+    }
+    if let Some(&IfModifiedSince(since)) = headers.get::<IfModifiedSince>() {
+        let since_secs = SystemTime::from(since)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        return modified_secs > since_secs;
+    }
+    true
+}
+
+
+// Whether the client's If-Range, if it sent one, says that its partial copy is of this version of
+// the file. As per RFC 7233, entity tags must match strongly and dates exactly. If they don't, or
+// we can't understand If-Range, we ignore the Range and send the whole file.
+fn if_range_matches(headers: &Headers, etag: &EntityTag, modified_secs: u64) -> bool {
+    match headers.get::<IfRange>() {
+        Some(&IfRange::EntityTag(ref tag)) => tag.strong_eq(etag),
+        Some(&IfRange::Date(date)) => {
+            SystemTime::from(date)
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() == modified_secs)
+                .unwrap_or(false)
+        }
+        None => headers.get_raw("If-Range").is_none(),
+    }
+}
+
+
+// The range of bytes [start, end) the client asked for, if it asked for a single range. We serve
+// the whole file for multiple ranges. Err means the range can't be satisfied.
+fn requested_range(headers: &Headers, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let specs = match headers.get::<Range>() {
+        Some(&Range::Bytes(ref specs)) if specs.len() == 1 => specs,
+        _ => return Ok(None),
+    };
+    let (start, end) = match specs[0] {
+        ByteRangeSpec::FromTo(start, end) => (start, end.saturating_add(1)),
+        ByteRangeSpec::AllFrom(start) => (start, len),
+        ByteRangeSpec::Last(n) => (len.saturating_sub(n), len),
+    };
+    let end = ::std::cmp::min(end, len);
+    if start >= end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+
+fn empty_response<C>(status: StatusCode) -> Response<BodyStream<C>>
+    where C: ChannelLayer
+{
+    Response::new()
+        .with_status(status)
+        .with_header(ContentLength(0))
+        .with_body(BodyStream::empty())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{if_range_matches, is_contained, requested_range, resolve_under};
+
+    use std::fs;
+
+    use hyper::Headers;
+    use hyper::header::EntityTag;
+
+    use test_utils::TempDir;
+
+    #[test]
+    fn test_resolve_under() {
+        let dir = TempDir::new("static");
+        fs::create_dir(dir.path().join("css")).unwrap();
+        fs::File::create(dir.path().join("css").join("site.css")).unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        assert_eq!(resolve_under(&root, "/css/site.css"), Some(root.join("css").join("site.css")));
+        assert_eq!(resolve_under(&root, "/css/./site.css"),
+                   Some(root.join("css").join("site.css")));
+        assert_eq!(resolve_under(&root, "/css/missing.css"), None);
+        assert_eq!(resolve_under(&root, "/css/../css/site.css"), None);
+        assert_eq!(resolve_under(&root, "/../etc/passwd"), None);
+        assert_eq!(resolve_under(&root, "/css\\..\\site.css"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_contained() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("static-contained");
+        fs::File::create(dir.path().join("site.css")).unwrap();
+        symlink("/etc/passwd", dir.path().join("site.css.gz")).unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        assert!(is_contained(&root, &root.join("site.css")));
+        assert!(!is_contained(&root, &root.join("site.css.gz")));
+        assert!(!is_contained(&root, &root.join("site.css.br")));
+    }
+
+    #[test]
+    fn test_requested_range() {
+        let mut headers = Headers::new();
+        assert_eq!(requested_range(&headers, 100), Ok(None));
+
+        headers.set_raw("Range", "bytes=10-19");
+        assert_eq!(requested_range(&headers, 100), Ok(Some((10, 20))));
+        headers.set_raw("Range", "bytes=90-");
+        assert_eq!(requested_range(&headers, 100), Ok(Some((90, 100))));
+        headers.set_raw("Range", "bytes=-10");
+        assert_eq!(requested_range(&headers, 100), Ok(Some((90, 100))));
+        headers.set_raw("Range", "bytes=90-200");
+        assert_eq!(requested_range(&headers, 100), Ok(Some((90, 100))));
+        headers.set_raw("Range", "bytes=100-");
+        assert_eq!(requested_range(&headers, 100), Err(()));
+        headers.set_raw("Range", "bytes=0-1,5-6");
+        assert_eq!(requested_range(&headers, 100), Ok(None));
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let etag = EntityTag::strong("5a-64".to_owned());
+        let mut headers = Headers::new();
+        assert!(if_range_matches(&headers, &etag, 90));
+
+        headers.set_raw("If-Range", "\"5a-64\"");
+        assert!(if_range_matches(&headers, &etag, 90));
+        headers.set_raw("If-Range", "\"5b-64\"");
+        assert!(!if_range_matches(&headers, &etag, 90));
+        headers.set_raw("If-Range", "W/\"5a-64\"");
+        assert!(!if_range_matches(&headers, &etag, 90));
+
+        // Thu, 01 Jan 1970 00:01:30 GMT is 90 seconds after the epoch.
+        headers.set_raw("If-Range", "Thu, 01 Jan 1970 00:01:30 GMT");
+        assert!(if_range_matches(&headers, &etag, 90));
+        assert!(!if_range_matches(&headers, &etag, 91));
+
+        headers.set_raw("If-Range", "yesterday");
+        assert!(!if_range_matches(&headers, &etag, 90));
+    }
+}
@@ -0,0 +1,40 @@
+// Helpers shared by the unit tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rand::{thread_rng, Rng};
+
+
+// Headers as we pass them around once they've been taken out of an ASGI message.
+pub fn headers(headers: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    headers.iter()
+        .map(|&(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+
+// A directory of our own under the system's temporary directory, so that tests running at the
+// same time don't trip over each other's files. It's removed along with its contents when it's
+// dropped, even if the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let suffix: String = thread_rng().gen_ascii_chars().take(10).collect();
+        let path = env::temp_dir().join(format!("asgi-server-{}-{}", name, suffix));
+        fs::create_dir(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,280 @@
+use std;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use rustls;
+use rustls::{ResolvesServerCert, ServerConfig, SignatureScheme};
+use rustls::internal::pemfile;
+use rustls::sign::{CertifiedKey, RSASigningKey, SigningKey};
+
+
+/// A certificate and private key to serve over TLS, read from PEM files. If a server name is
+/// given, then the certificate is only served to clients asking for that name using SNI.
+#[derive(Clone, Debug)]
+pub struct CertificatePaths {
+    pub server_name: Option<String>,
+    pub cert: String,
+    pub key: String,
+}
+
+impl CertificatePaths {
+    /// Parses a `--tls-cert` value of the form `[SERVER_NAME=]CERT.pem`, pairing it with the
+    /// corresponding `--tls-key` value. Server names can't contain '=' or '/', so anything else
+    /// is taken to be part of the path. A value naming a file that exists is always taken to be
+    /// a path, even if it contains '=' (e.g. `a=b.pem`).
+    pub fn parse(cert: &str, key: &str) -> Self {
+        CertificatePaths::parse_with(cert, key, |path| Path::new(path).is_file())
+    }
+
+    fn parse_with<F>(cert: &str, key: &str, is_file: F) -> Self
+        where F: Fn(&str) -> bool
+    {
+        let mut parts = cert.splitn(2, '=');
+        let (server_name, cert) = match (parts.next(), parts.next()) {
+            (Some(server_name), Some(path)) if !server_name.contains('/') && !is_file(cert) => {
+                (Some(server_name.to_lowercase()), path)
+            }
+            _ => (None, cert),
+        };
+        CertificatePaths {
+            server_name: server_name,
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+        }
+    }
+
+    fn load(&self) -> Result<CertifiedKey, TlsError> {
+        let certs = pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))
+            .map_err(|_| TlsError::InvalidCertificate(self.cert.clone()))?;
+        if certs.is_empty() {
+            return Err(TlsError::InvalidCertificate(self.cert.clone()));
+        }
+
+        // Only RSA keys can be used for signing, but they may be in either PKCS#8 or the
+        // traditional RSA format.
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(&self.key)?))
+            .map_err(|_| TlsError::InvalidKey(self.key.clone()))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(&self.key)?))
+                .map_err(|_| TlsError::InvalidKey(self.key.clone()))?;
+        }
+        let key = match keys.into_iter().next() {
+            Some(key) => key,
+            None => return Err(TlsError::InvalidKey(self.key.clone())),
+        };
+        let signing_key = RSASigningKey::new(&key)
+            .map_err(|_| TlsError::InvalidKey(self.key.clone()))?;
+
+        Ok(CertifiedKey::new(certs, Arc::new(Box::new(signing_key) as Box<SigningKey>)))
+    }
+}
+
+
+// The certificates we've loaded from disk. We serve the certificate whose server name matches
+// the one the client asked for, falling back to the first certificate without a server name (or
+// just the first certificate, if they all have one).
+struct LoadedCertificates {
+    default: CertifiedKey,
+    by_server_name: HashMap<String, CertifiedKey>,
+}
+
+impl LoadedCertificates {
+    fn load(paths: &[CertificatePaths]) -> Result<Self, TlsError> {
+        let mut default = None;
+        let mut by_server_name = HashMap::new();
+        for path in paths {
+            let cert = path.load()?;
+            match path.server_name {
+                Some(ref server_name) => {
+                    by_server_name.insert(server_name.clone(), cert.clone());
+                    if default.is_none() {
+                        default = Some((false, cert));
+                    }
+                }
+                None => {
+                    match default {
+                        Some((true, _)) => {}
+                        _ => default = Some((true, cert)),
+                    }
+                }
+            }
+        }
+
+        match default {
+            Some((_, default)) => {
+                Ok(LoadedCertificates {
+                    default: default,
+                    by_server_name: by_server_name,
+                })
+            }
+            None => Err(TlsError::NoCertificates),
+        }
+    }
+}
+
+
+struct CertificateResolver {
+    paths: Vec<CertificatePaths>,
+    certs: RwLock<LoadedCertificates>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self,
+               server_name: Option<&str>,
+               _: &[SignatureScheme])
+               -> Option<CertifiedKey> {
+        let certs = self.certs.read().unwrap();
+        let cert = server_name.map(str::to_lowercase)
+            .and_then(|server_name| certs.by_server_name.get(&server_name));
+        Some(cert.unwrap_or(&certs.default).clone())
+    }
+}
+
+
+/// The TLS configuration of the server. Certificates are read from disk when it is created, and
+/// can be re-read later with `reload()` without interrupting connections in progress.
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<ServerConfig>,
+    resolver: Arc<CertificateResolver>,
+}
+
+impl Tls {
+    pub fn new(paths: Vec<CertificatePaths>) -> Result<Self, TlsError> {
+        let certs = LoadedCertificates::load(&paths)?;
+        let resolver = Arc::new(CertificateResolver {
+            paths: paths,
+            certs: RwLock::new(certs),
+        });
+
+        let mut config = ServerConfig::new();
+        config.cert_resolver = Box::new(SharedResolver(resolver.clone()));
+        // Hyper can only speak HTTP/1.x on the wire for now, so don't offer h2 over ALPN.
+        config.set_protocols(&["http/1.1".to_owned()]);
+
+        Ok(Tls {
+            config: Arc::new(config),
+            resolver: resolver,
+        })
+    }
+
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.clone()
+    }
+
+    /// Re-reads all certificates from disk. If any of them fail to load, we carry on serving the
+    /// ones we already have.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certs = LoadedCertificates::load(&self.resolver.paths)?;
+        *self.resolver.certs.write().unwrap() = certs;
+        Ok(())
+    }
+}
+
+
+// ServerConfig wants to own its resolver, but we need to keep hold of it to reload certificates.
+struct SharedResolver(Arc<CertificateResolver>);
+
+impl ResolvesServerCert for SharedResolver {
+    fn resolve(&self,
+               server_name: Option<&str>,
+               sigschemes: &[SignatureScheme])
+               -> Option<CertifiedKey> {
+        self.0.resolve(server_name, sigschemes)
+    }
+}
+
+
+#[derive(Debug)]
+pub enum TlsError {
+    NoCertificates,
+    InvalidCertificate(String),
+    InvalidKey(String),
+
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            TlsError::NoCertificates => write!(f, "No certificates given"),
+            TlsError::InvalidCertificate(ref path) => write!(f, "Invalid certificate: {}", path),
+            TlsError::InvalidKey(ref path) => {
+                write!(f, "Invalid private key (only RSA keys are supported): {}", path)
+            }
+
+            TlsError::Io(ref err) => write!(f, "Error reading certificate: {}", err),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn description(&self) -> &str {
+        match *self {
+            TlsError::NoCertificates => "No certificates given",
+            TlsError::InvalidCertificate(_) => "Invalid certificate",
+            TlsError::InvalidKey(_) => "Invalid private key",
+
+            TlsError::Io(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            TlsError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TlsError {
+    fn from(err: std::io::Error) -> TlsError {
+        TlsError::Io(err)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::CertificatePaths;
+
+    #[test]
+    fn test_certificate_paths_parse() {
+        let paths = CertificatePaths::parse("cert.pem", "key.pem");
+        assert_eq!(paths.server_name, None);
+        assert_eq!(paths.cert, "cert.pem");
+        assert_eq!(paths.key, "key.pem");
+
+        let paths = CertificatePaths::parse("Example.com=/etc/cert.pem", "key.pem");
+        assert_eq!(paths.server_name, Some("example.com".to_owned()));
+        assert_eq!(paths.cert, "/etc/cert.pem");
+
+        let paths = CertificatePaths::parse("example.com=/etc/a=b.pem", "key.pem");
+        assert_eq!(paths.server_name, Some("example.com".to_owned()));
+        assert_eq!(paths.cert, "/etc/a=b.pem");
+
+        let paths = CertificatePaths::parse("/etc/a=b.pem", "key.pem");
+        assert_eq!(paths.server_name, None);
+        assert_eq!(paths.cert, "/etc/a=b.pem");
+    }
+
+    #[test]
+    fn test_certificate_paths_parse_relative() {
+        // A relative path containing '=' is only a path if there's a file there.
+        let paths = CertificatePaths::parse_with("a=b.pem", "key.pem", |path| path == "a=b.pem");
+        assert_eq!(paths.server_name, None);
+        assert_eq!(paths.cert, "a=b.pem");
+
+        let paths = CertificatePaths::parse_with("a=b.pem", "key.pem", |_| false);
+        assert_eq!(paths.server_name, Some("a".to_owned()));
+        assert_eq!(paths.cert, "b.pem");
+
+        let paths = CertificatePaths::parse("./a=b.pem", "key.pem");
+        assert_eq!(paths.server_name, None);
+        assert_eq!(paths.cert, "./a=b.pem");
+    }
+}
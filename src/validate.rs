@@ -0,0 +1,156 @@
+use std;
+use std::ascii::AsciiExt;
+
+
+// Headers describing the connection rather than the response. These are Hyper's business, and an
+// application setting them would at best confuse the client.
+const HOP_BY_HOP_HEADERS: &'static [&'static str] = &["connection",
+                                                      "keep-alive",
+                                                      "proxy-connection",
+                                                      "te",
+                                                      "trailer",
+                                                      "transfer-encoding",
+                                                      "upgrade"];
+
+
+/// Why a response from the application can't be sent to the client.
+#[derive(Debug, PartialEq)]
+pub enum InvalidResponse {
+    Status(u16),
+    HeaderName(Vec<u8>),
+    HeaderValue(String),
+    // Content-Length was given more than once, or isn't a number, so the client can't tell where
+    // the response ends.
+    ContentLength,
+}
+
+impl std::fmt::Display for InvalidResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            InvalidResponse::Status(status) => write!(f, "Invalid status code: {}", status),
+            InvalidResponse::HeaderName(ref name) => {
+                write!(f, "Invalid header name: {:?}", String::from_utf8_lossy(name))
+            }
+            InvalidResponse::HeaderValue(ref name) => {
+                write!(f, "Invalid value for header: {}", name)
+            }
+            InvalidResponse::ContentLength => write!(f, "Invalid or repeated Content-Length"),
+        }
+    }
+}
+
+
+/// Checks that a response's status and headers are safe to write to the client, removing any
+/// hop-by-hop headers the application shouldn't have set. Returns the headers to send, and the
+/// names of those removed.
+pub fn sanitize_response(status: u16,
+                         headers: Vec<(Vec<u8>, Vec<u8>)>)
+                         -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Vec<String>), InvalidResponse> {
+    // Informational (1xx) statuses aren't final, so the client would go on waiting for the response
+    // after one.
+    if status < 200 || status > 599 {
+        return Err(InvalidResponse::Status(status));
+    }
+
+    let mut sanitized = Vec::with_capacity(headers.len());
+    let mut removed = Vec::new();
+    let mut has_content_length = false;
+    for (name, value) in headers {
+        if name.is_empty() || !name.iter().all(|c| is_token_char(*c)) {
+            return Err(InvalidResponse::HeaderName(name));
+        }
+        // Everything in a valid name is ASCII, so this can't fail.
+        let name_str = String::from_utf8(name.clone()).unwrap();
+        if value.iter().any(|c| *c == b'\r' || *c == b'\n' || *c == b'\0') {
+            return Err(InvalidResponse::HeaderValue(name_str));
+        }
+        if name_str.eq_ignore_ascii_case("content-length") {
+            let is_number = !value.is_empty() && value.iter().all(|c| b'0' <= *c && *c <= b'9');
+            if has_content_length || !is_number {
+                return Err(InvalidResponse::ContentLength);
+            }
+            has_content_length = true;
+        }
+        if is_hop_by_hop(&name_str) {
+            removed.push(name_str);
+            continue;
+        }
+        sanitized.push((name, value));
+    }
+    Ok((sanitized, removed))
+}
+
+
+// tchar, as defined by RFC 7230.
+fn is_token_char(c: u8) -> bool {
+    (c as char).is_ascii() && (c as char).is_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(name))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize_response, InvalidResponse};
+
+    use test_utils::headers;
+
+    #[test]
+    fn test_valid_response() {
+        let valid = headers(&[("Content-Type", "text/html"), ("set-cookie", "a=b; Path=/")]);
+        assert_eq!(sanitize_response(200, valid.clone()), Ok((valid, vec![])));
+    }
+
+    #[test]
+    fn test_invalid_status() {
+        assert_eq!(sanitize_response(99, vec![]), Err(InvalidResponse::Status(99)));
+        assert_eq!(sanitize_response(600, vec![]), Err(InvalidResponse::Status(600)));
+        assert_eq!(sanitize_response(100, vec![]), Err(InvalidResponse::Status(100)));
+        assert_eq!(sanitize_response(103, vec![]), Err(InvalidResponse::Status(103)));
+    }
+
+    #[test]
+    fn test_invalid_content_length() {
+        let valid = headers(&[("Content-Length", "42")]);
+        assert_eq!(sanitize_response(200, valid.clone()), Ok((valid, vec![])));
+        for invalid in &[&[("Content-Length", "42"), ("content-length", "42")][..],
+                         &[("Content-Length", "42"), ("Content-Length", "43")][..],
+                         &[("Content-Length", "42, 42")][..],
+                         &[("Content-Length", "-1")][..],
+                         &[("Content-Length", "")][..]] {
+            assert_eq!(sanitize_response(200, headers(invalid)),
+                       Err(InvalidResponse::ContentLength));
+        }
+    }
+
+    #[test]
+    fn test_invalid_header_name() {
+        assert_eq!(sanitize_response(200, headers(&[("Bad Header", "x")])),
+                   Err(InvalidResponse::HeaderName(b"Bad Header".to_vec())));
+        assert_eq!(sanitize_response(200, headers(&[("X-Bad:", "x")])),
+                   Err(InvalidResponse::HeaderName(b"X-Bad:".to_vec())));
+        assert_eq!(sanitize_response(200, headers(&[("", "x")])),
+                   Err(InvalidResponse::HeaderName(vec![])));
+        assert!(sanitize_response(200, vec![("Caf\u{e9}".as_bytes().to_vec(), vec![])]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_header_value() {
+        assert_eq!(sanitize_response(200, headers(&[("Location", "/\r\nSet-Cookie: a=b")])),
+                   Err(InvalidResponse::HeaderValue("Location".to_owned())));
+        assert_eq!(sanitize_response(200, headers(&[("X-Null", "a\0b")])),
+                   Err(InvalidResponse::HeaderValue("X-Null".to_owned())));
+    }
+
+    #[test]
+    fn test_hop_by_hop_headers_removed() {
+        let response = headers(&[("Transfer-Encoding", "chunked"),
+                                 ("Connection", "close"),
+                                 ("Content-Type", "text/plain")]);
+        assert_eq!(sanitize_response(200, response),
+                   Ok((headers(&[("Content-Type", "text/plain")]),
+                       vec!["Transfer-Encoding".to_owned(), "Connection".to_owned()])));
+    }
+}
@@ -0,0 +1,110 @@
+use std;
+use std::error::Error;
+use std::str::FromStr;
+
+use redis::IntoConnectionInfo;
+
+use channels::redis::DEFAULT_PREFIX;
+use routing::normalize_host;
+
+
+/// An application served for requests with a particular Host, through its own Redis database or
+/// key prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VirtualHost {
+    pub host: String,
+    pub redis_url: String,
+    pub prefix: String,
+}
+
+/// Parses a `--vhost` value of the form `HOST=REDIS_URL[,prefix=PREFIX]`, e.g.
+/// `blog.example.com=redis://127.0.0.1/1,prefix=blog:`.
+impl FromStr for VirtualHost {
+    type Err = VirtualHostParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let target = options.next().unwrap_or("");
+        let index = target.find('=').ok_or(VirtualHostParseError::Invalid)?;
+        let (host, redis_url) = (&target[..index], &target[index + 1..]);
+        if host.is_empty() {
+            return Err(VirtualHostParseError::Invalid);
+        }
+        if redis_url.into_connection_info().is_err() {
+            return Err(VirtualHostParseError::InvalidRedisUrl(redis_url.to_owned()));
+        }
+
+        let mut vhost = VirtualHost {
+            host: normalize_host(host),
+            redis_url: redis_url.to_owned(),
+            prefix: DEFAULT_PREFIX.to_owned(),
+        };
+        for option in options {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next().unwrap_or("").trim(), parts.next()) {
+                ("prefix", Some(prefix)) if !prefix.is_empty() => vhost.prefix = prefix.to_owned(),
+                (key, _) => return Err(VirtualHostParseError::UnknownOption(key.to_owned())),
+            }
+        }
+        Ok(vhost)
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum VirtualHostParseError {
+    Invalid,
+    InvalidRedisUrl(String),
+    UnknownOption(String),
+}
+
+impl std::fmt::Display for VirtualHostParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            VirtualHostParseError::Invalid => {
+                write!(f, "Virtual hosts must be of the form HOST=REDIS_URL[,prefix=PREFIX]")
+            }
+            VirtualHostParseError::InvalidRedisUrl(ref url) => {
+                write!(f, "Invalid Redis URL: {}", url)
+            }
+            VirtualHostParseError::UnknownOption(ref option) => {
+                write!(f, "Unknown virtual host option: {}", option)
+            }
+        }
+    }
+}
+
+impl Error for VirtualHostParseError {
+    fn description(&self) -> &str {
+        match *self {
+            VirtualHostParseError::Invalid => "Invalid virtual host",
+            VirtualHostParseError::InvalidRedisUrl(_) => "Invalid Redis URL",
+            VirtualHostParseError::UnknownOption(_) => "Unknown virtual host option",
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{VirtualHost, VirtualHostParseError};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("Blog.example.com=redis://127.0.0.1/1,prefix=blog:".parse(),
+                   Ok(VirtualHost {
+                       host: "blog.example.com".to_owned(),
+                       redis_url: "redis://127.0.0.1/1".to_owned(),
+                       prefix: "blog:".to_owned(),
+                   }));
+        let vhost: VirtualHost = "shop.example.com=redis://10.0.0.5".parse().unwrap();
+        assert_eq!(vhost.prefix, "asgi:");
+
+        assert_eq!("redis://127.0.0.1".parse::<VirtualHost>(),
+                   Err(VirtualHostParseError::Invalid));
+        assert_eq!("blog.example.com=http://127.0.0.1".parse::<VirtualHost>(),
+                   Err(VirtualHostParseError::InvalidRedisUrl("http://127.0.0.1".to_owned())));
+        assert_eq!("blog.example.com=redis://127.0.0.1,db=2".parse::<VirtualHost>(),
+                   Err(VirtualHostParseError::UnknownOption("db".to_owned())));
+    }
+}
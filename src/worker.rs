@@ -0,0 +1,403 @@
+use std;
+use std::ascii::AsciiExt;
+use std::time::{Duration, Instant};
+
+use serde::bytes::ByteBuf;
+
+use channels::{ChannelError, ChannelLayer};
+use msgs;
+
+
+// How long we'll wait for the next chunk of a request's body before giving up on the request.
+const BODY_TIMEOUT_SECS: u64 = 60;
+// The most content we'll put in a single response message, to keep clear of the channel layer's
+// message size limits.
+const RESPONSE_CHUNK_SIZE: usize = 256 * 1024;
+
+
+/// A request received over ASGI, with its body reassembled.
+#[derive(Debug)]
+pub struct Request {
+    pub http_version: String,
+    pub method: String,
+    pub scheme: String,
+    pub path: String,
+    pub root_path: String,
+    pub query_string: String,
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub client: Option<(String, u16)>,
+    pub server: Option<(String, u16)>,
+}
+
+impl Request {
+    /// The value of the first header with the given name, if there is one.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|&(_, ref value)| &value[..])
+    }
+}
+
+
+/// A response for a worker to send back to the server.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub content: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            content: Vec::new(),
+        }
+    }
+
+    pub fn with_header<N, V>(mut self, name: N, value: V) -> Self
+        where N: Into<Vec<u8>>,
+              V: Into<Vec<u8>>
+    {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_content<B>(mut self, content: B) -> Self
+        where B: Into<Vec<u8>>
+    {
+        self.content = content.into();
+        self
+    }
+}
+
+
+/// Something which can turn a request into a response.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+    where F: Fn(&Request) -> Response + Send + Sync
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+
+struct Route {
+    method: String,
+    path: String,
+    handler: Box<Handler>,
+}
+
+impl Route {
+    // Routes match their path exactly, unless it ends with /*, in which case they match anything
+    // beneath it.
+    fn matches_path(&self, path: &str) -> bool {
+        match self.path.ends_with("/*") {
+            true => path.starts_with(&self.path[..self.path.len() - 1]),
+            false => path == self.path,
+        }
+    }
+}
+
+
+/// Dispatches requests to handlers by method and path. The first matching route wins.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Adds a route for requests with the given method and path, e.g. "/users/*" for every path
+    /// beneath /users/.
+    pub fn route<H>(mut self, method: &str, path: &str, handler: H) -> Self
+        where H: 'static + Handler
+    {
+        self.routes.push(Route {
+            method: method.to_uppercase(),
+            path: path.to_owned(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H>(self, path: &str, handler: H) -> Self
+        where H: 'static + Handler
+    {
+        self.route("GET", path, handler)
+    }
+
+    pub fn post<H>(self, path: &str, handler: H) -> Self
+        where H: 'static + Handler
+    {
+        self.route("POST", path, handler)
+    }
+
+    /// Handles a request using the first matching route. HEAD requests without a route of their
+    /// own are handled by the GET route, without its body. Requests for unknown paths get a 404,
+    /// and requests for known paths with the wrong method get a 405.
+    pub fn handle(&self, request: &Request) -> Response {
+        let routes: Vec<&Route> = self.routes
+            .iter()
+            .filter(|route| route.matches_path(&request.path))
+            .collect();
+        if routes.is_empty() {
+            return Response::new(404).with_content("Not Found");
+        }
+        if let Some(route) = routes.iter().find(|route| route.method == request.method) {
+            return route.handler.handle(request);
+        }
+        if request.method == "HEAD" {
+            if let Some(route) = routes.iter().find(|route| route.method == "GET") {
+                let mut response = route.handler.handle(request);
+                response.content.clear();
+                return response;
+            }
+        }
+
+        let mut allowed: Vec<&str> = routes.iter().map(|route| &route.method[..]).collect();
+        if allowed.contains(&"GET") {
+            allowed.push("HEAD");
+        }
+        allowed.sort();
+        allowed.dedup();
+        Response::new(405)
+            .with_header("Allow", allowed.join(", "))
+            .with_content("Method Not Allowed")
+    }
+}
+
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let routes: Vec<(&str, &str)> =
+            self.routes.iter().map(|route| (&route.method[..], &route.path[..])).collect();
+        f.debug_struct("Router").field("routes", &routes).finish()
+    }
+}
+
+
+/// Serves http.request messages from a channel layer using a router.
+///
+/// Workers take every request on their channel, replying 404 to any the router doesn't match, so
+/// they need a channel of their own rather than sharing http.request with another application
+/// (e.g. Django). Route requests to it with `--route`, e.g. `--route /api=http.request.api`.
+pub struct Worker<C>
+    where C: ChannelLayer
+{
+    channel_layer: C,
+    router: Router,
+    channels: Vec<String>,
+}
+
+impl<C> Worker<C>
+    where C: ChannelLayer
+{
+    /// Receives requests from the given channel, which should be one that only this worker's
+    /// routes are sent to.
+    pub fn new(channel_layer: C, channel: &str, router: Router) -> Self {
+        Worker {
+            channel_layer: channel_layer,
+            router: router,
+            channels: vec![channel.to_owned()],
+        }
+    }
+
+    /// Serves requests forever. Only returns if the channel layer fails.
+    pub fn run(&self) -> Result<(), ChannelError> {
+        loop {
+            self.handle_next()?;
+        }
+    }
+
+    /// Waits for the next request and handles it. Errors for individual requests are logged
+    /// rather than returned, so that one bad request can't stop the worker.
+    pub fn handle_next(&self) -> Result<(), ChannelError> {
+        let (_, reply) = match self.channel_layer.receive(self.channels.iter(), true)? {
+            Some(received) => received,
+            None => return Ok(()),
+        };
+        let message = match C::deserialize::<msgs::http::OwnedRequest>(reply) {
+            Ok(message) => message,
+            Err(err) => {
+                println!("Ignoring invalid request message: {}", err);
+                return Ok(());
+            }
+        };
+        let reply_channel = message.reply_channel.clone();
+
+        let request = match self.read_request(message) {
+            Ok(Some(request)) => request,
+            // The client went away before sending all of its body.
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                println!("Failed to read request body for {}: {}", reply_channel, err);
+                return Ok(());
+            }
+        };
+
+        let response = self.router.handle(&request);
+        if let Err(err) = self.send_response(&reply_channel, response) {
+            println!("Failed to send response on {}: {}", reply_channel, err);
+        }
+        Ok(())
+    }
+
+    // Builds a request from its message, receiving the rest of its body if there is any. Returns
+    // None if the body was abandoned.
+    fn read_request(&self,
+                    message: msgs::http::OwnedRequest)
+                    -> Result<Option<Request>, ChannelError> {
+        let mut body: Vec<u8> = message.body.into();
+        if let Some(body_channel) = message.body_channel {
+            let channels = vec![body_channel];
+            let timeout = Duration::from_secs(BODY_TIMEOUT_SECS);
+            let mut last_chunk = Instant::now();
+            loop {
+                let reply = match self.channel_layer.receive(channels.iter(), true)? {
+                    Some((_, reply)) => reply,
+                    None if last_chunk.elapsed() < timeout => continue,
+                    None => return Ok(None),
+                };
+                last_chunk = Instant::now();
+                let chunk: msgs::http::OwnedRequestBodyChunk = C::deserialize(reply)?;
+                if chunk.closed {
+                    return Ok(None);
+                }
+                body.extend_from_slice(&chunk.content);
+                if !chunk.more_content {
+                    break;
+                }
+            }
+        }
+
+        Ok(Some(Request {
+            http_version: message.http_version,
+            method: message.method.to_uppercase(),
+            scheme: message.scheme,
+            path: message.path,
+            root_path: message.root_path,
+            query_string: message.query_string,
+            headers: message.headers
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+            body: body,
+            client: message.client,
+            server: message.server,
+        }))
+    }
+
+    // Sends the response, splitting large bodies over several messages.
+    fn send_response(&self, reply_channel: &str, response: Response) -> Result<(), ChannelError> {
+        let mut chunks = response.content.chunks(RESPONSE_CHUNK_SIZE);
+        let first = chunks.next().unwrap_or(&[]);
+        let rest: Vec<&[u8]> = chunks.collect();
+
+        self.channel_layer
+            .send(reply_channel,
+                  &msgs::http::Response {
+                      status: response.status,
+                      headers: response.headers
+                          .into_iter()
+                          .map(|(name, value)| (ByteBuf::from(name), ByteBuf::from(value)))
+                          .collect(),
+                      content: ByteBuf::from(first.to_vec()),
+                      more_content: !rest.is_empty(),
+                      trailers: Vec::new(),
+                  })?;
+        for (i, chunk) in rest.iter().enumerate() {
+            self.channel_layer
+                .send(reply_channel,
+                      &msgs::http::ResponseBodyChunk {
+                          content: ByteBuf::from(chunk.to_vec()),
+                          more_content: i + 1 < rest.len(),
+                          trailers: Vec::new(),
+                      })?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Request, Response, Router};
+
+    fn request(method: &str, path: &str) -> Request {
+        Request {
+            http_version: "1.1".to_owned(),
+            method: method.to_owned(),
+            scheme: "http".to_owned(),
+            path: path.to_owned(),
+            root_path: String::new(),
+            query_string: String::new(),
+            headers: vec![(b"Content-Type".to_vec(), b"text/plain".to_vec())],
+            body: Vec::new(),
+            client: None,
+            server: None,
+        }
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_: &Request| Response::new(200).with_content("index"))
+            .post("/", |_: &Request| Response::new(201))
+            .get("/users/*", |req: &Request| Response::new(200).with_content(req.path.clone()))
+    }
+
+    #[test]
+    fn test_request_header() {
+        let req = request("GET", "/");
+        assert_eq!(req.header("content-type"), Some(&b"text/plain"[..]));
+        assert_eq!(req.header("accept"), None);
+    }
+
+    #[test]
+    fn test_router() {
+        let router = router();
+        assert_eq!(router.handle(&request("GET", "/")),
+                   Response::new(200).with_content("index"));
+        assert_eq!(router.handle(&request("POST", "/")).status, 201);
+        assert_eq!(router.handle(&request("GET", "/users/42")).content, b"/users/42".to_vec());
+        assert_eq!(router.handle(&request("GET", "/users")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/missing")).status, 404);
+    }
+
+    #[test]
+    fn test_router_method_not_allowed() {
+        let response = router().handle(&request("DELETE", "/"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers, vec![(b"Allow".to_vec(), b"GET, HEAD, POST".to_vec())]);
+
+        // Each method is only listed once, however many routes allow it.
+        let router = Router::new()
+            .get("/users/42", |_: &Request| Response::new(200))
+            .post("/users/*", |_: &Request| Response::new(201))
+            .get("/users/*", |_: &Request| Response::new(200));
+        let response = router.handle(&request("DELETE", "/users/42"));
+        assert_eq!(response.headers, vec![(b"Allow".to_vec(), b"GET, HEAD, POST".to_vec())]);
+    }
+
+    #[test]
+    fn test_router_head() {
+        let router = router();
+        assert_eq!(router.handle(&request("HEAD", "/")), Response::new(200));
+        assert_eq!(router.handle(&request("HEAD", "/users/42")), Response::new(200));
+
+        let router = Router::new()
+            .route("HEAD", "/", |_: &Request| Response::new(204))
+            .get("/", |_: &Request| Response::new(200).with_content("index"));
+        assert_eq!(router.handle(&request("HEAD", "/")).status, 204);
+    }
+}